@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use serenity::http::Http;
+use serenity::model::id::*;
+
+use tracing::error;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FailureSource {
+    Login(String),
+    Course(u32)
+}
+
+pub struct FailureTracker {
+    threshold: u32,
+    failures: HashMap<FailureSource, u32>
+}
+
+impl FailureTracker {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            failures: HashMap::new()
+        }
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    // True exactly once, when the consecutive failure count reaches the threshold
+    pub fn failure(&mut self, source: FailureSource) -> bool {
+        let count = self.failures.entry(source).or_insert(0);
+        *count += 1;
+        *count == self.threshold
+    }
+
+    // True if the source had been alerted on and is working again
+    pub fn success(&mut self, source: FailureSource) -> bool {
+        match self.failures.remove(&source) {
+            Some(count) => count >= self.threshold,
+            None => false
+        }
+    }
+}
+
+impl fmt::Display for FailureSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FailureSource::Login(account) => write!(f, "Moodle login for account {}", account),
+            FailureSource::Course(id) => write!(f, "polling course {}", id)
+        }
+    }
+}
+
+pub async fn send_alert(http: &Arc<Http>, channel: Option<ChannelId>, user: Option<UserId>, text: &str) {
+    if let Some(channel) = channel {
+        if let Err(e) = channel.say(http, text).await {
+            error!("Error sending alert: {}", e);
+        }
+    }
+
+    if let Some(user) = user {
+        match user.create_dm_channel(http).await {
+            Ok(dm) => if let Err(e) = dm.say(http, text).await {
+                error!("Error sending alert: {}", e);
+            },
+            Err(e) => error!("Error opening DM channel for alert: {}", e)
+        }
+    }
+}
+
+#[test]
+fn test_failure_tracker() {
+    let mut tracker = FailureTracker::new(3);
+    let login = FailureSource::Login("default".to_string());
+
+    assert!(!tracker.failure(FailureSource::Course(1)));
+    assert!(!tracker.failure(FailureSource::Course(1)));
+    assert!(!tracker.success(FailureSource::Course(1)));
+
+    assert!(!tracker.failure(login.clone()));
+    assert!(!tracker.failure(login.clone()));
+    assert!(tracker.failure(login.clone()));
+    assert!(!tracker.failure(login.clone()));
+    assert!(!tracker.failure(FailureSource::Course(1)));
+
+    assert!(tracker.success(login.clone()));
+    assert!(!tracker.success(login));
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::process::exit;
+
+use tokio::sync::Mutex;
+
+use tracing::{error, info, warn};
+
+use crate::conf::Conf;
+use crate::export::*;
+use crate::mirror::Mirror;
+use crate::moodle::*;
+
+const USAGE: &str = "Usage: poodle [--config <file>] [mirror [<dir>] | export <course> [json|md] | fetch <course> | diff <old.html> <new.html> | login-test]";
+
+pub async fn run(args: &[String], conf: Conf, mut accounts: HashMap<String, MoodleAuthConf>) {
+    match args[0].as_str() {
+        "mirror" if args.len() <= 2 => {
+            let context = Mutex::new(default_context(&conf, &mut accounts));
+            let dir = args.get(1).or(conf.mirror_dir.as_ref()).unwrap_or_else(|| {
+                eprintln!("No mirror directory given and mirror_dir missing from config");
+                usage()
+            });
+            let mirror = Mirror::new(dir);
+
+            let mut ids = conf.channels.iter().flat_map(|c| c.courses.iter().copied()).collect::<Vec<_>>();
+            ids.sort_unstable();
+            ids.dedup();
+
+            for id in ids {
+                let course = context.lock().await.get(id).await;
+                match course {
+                    Ok(course) => match mirror.sync(&context, &course).await {
+                        Ok(sync) if sync.failed > 0 => warn!(course = id, "Mirrored course, {} files downloaded, {} failed", sync.downloaded, sync.failed),
+                        Ok(sync) => info!(course = id, "Mirrored course, {} files downloaded", sync.downloaded),
+                        Err(e) => error!(course = id, "Failed to mirror course: {}", e)
+                    },
+                    Err(e) => error!(course = id, "Failed to fetch course data: {}", e)
+                }
+            }
+        },
+        "export" if args.len() == 2 || args.len() == 3 => {
+            let id = args[1].parse().unwrap_or_else(|_| usage());
+            let format = ExportFormat::parse(args.get(2).map(|s| s.as_str()).unwrap_or("json")).unwrap_or_else(|| usage());
+
+            let course = fetch_or_exit(&mut default_context(&conf, &mut accounts), id).await;
+            print!("{}", export(&course, format));
+        },
+        "fetch" if args.len() == 2 => {
+            let id = args[1].parse().unwrap_or_else(|_| usage());
+
+            let course = fetch_or_exit(&mut default_context(&conf, &mut accounts), id).await;
+            println!("{}", serde_json::to_string_pretty(&course).expect("Failed to serialise course"));
+        },
+        "login-test" if args.len() == 1 => {
+            let mut names = accounts.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+
+            let mut failed = false;
+            for name in names {
+                let mut context = MoodleContext::new(accounts.remove(&name).unwrap(), conf.file_tracking);
+                match context.login().await {
+                    Ok(()) => println!("{}: logged in", name),
+                    Err(e) => {
+                        println!("{}: {}", name, e);
+                        failed = true;
+                    }
+                }
+            }
+
+            if failed {
+                exit(1);
+            }
+        },
+        _ => usage()
+    }
+}
+
+// Compares two saved course pages, needs neither a config nor Moodle
+pub fn diff(args: &[String]) {
+    if args.len() != 3 {
+        usage();
+    }
+
+    let read = |path: &str| read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", path, e);
+        usage()
+    });
+    let origin = MoodleCourseData::from_html(&args[1], read(&args[1]));
+    let target = MoodleCourseData::from_html(&args[2], read(&args[2]));
+
+    match origin.user_diff(&target) {
+        Some(diff) => print!("{}", diff),
+        None => println!("No changes")
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2);
+}
+
+fn default_context(conf: &Conf, accounts: &mut HashMap<String, MoodleAuthConf>) -> MoodleContext {
+    let auth = accounts.remove(&conf.default_account).expect("Default Moodle account missing");
+    MoodleContext::new(auth, conf.file_tracking)
+}
+
+async fn fetch_or_exit(context: &mut MoodleContext, id: u32) -> MoodleCourseData {
+    match context.get(id).await {
+        Ok(course) => course,
+        Err(e) => {
+            error!(course = id, "Failed to fetch course data: {}", e);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,797 @@
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::{metadata, read_to_string};
+use std::net::ToSocketAddrs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use serenity::model::id::*;
+
+use config::*;
+
+use lettre::message::Mailbox;
+
+use crate::moodle::{FileTracking, MoodleAuthConf, MoodleChange, CHANGE_KINDS};
+use crate::email::{EmailConf, EmailMode, EmailNotifier, SmtpSecurity};
+use crate::logging::{self, LogFormat};
+use crate::metrics::COMMANDS;
+use crate::permissions::{Permissions, Rule};
+use crate::webhook::WebhookConf;
+
+const CONFIG_PATHS: &[&str] = &["./poodle.toml", "/etc/poodle/poodle.toml"];
+const SECRET_KEYS: &[&str] = &["user", "pass", "token", "email.user", "email.pass"];
+
+pub struct Conf {
+    pub path: String,
+    pub discord_token: String,
+    pub discord_client_id: String,
+    pub channels: Vec<ChannelConf>,
+    pub responses: Vec<String>,
+    pub admin_channel_id: Option<ChannelId>,
+    pub admin_user_id: Option<UserId>,
+    pub alert_threshold: u32,
+    pub default_account: String,
+    pub guild_accounts: HashMap<GuildId, String>,
+    pub file_tracking: FileTracking,
+    pub mirror_dir: Option<String>,
+    pub http_listen: Option<String>,
+    pub webhooks: Vec<WebhookConf>,
+    pub email: Option<EmailConf>,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub permissions: Permissions
+}
+
+impl Conf {
+    // Reads the config file given by --config, POODLE_CONFIG or found at one of CONFIG_PATHS, with POODLE_<KEY> environment
+    // variables (__ separating nested keys) taking precedence over it
+    pub fn load(path: Option<&str>) -> Result<(Self, HashMap<String, MoodleAuthConf>), ConfErr> {
+        Self::load_with(path, true)
+    }
+
+    // For the CLI subcommands, which talk to Moodle only and don't need the Discord keys
+    pub fn load_cli(path: Option<&str>) -> Result<(Self, HashMap<String, MoodleAuthConf>), ConfErr> {
+        Self::load_with(path, false)
+    }
+
+    fn load_with(path: Option<&str>, discord: bool) -> Result<(Self, HashMap<String, MoodleAuthConf>), ConfErr> {
+        let path = match path {
+            Some(path) => path,
+            None => CONFIG_PATHS.iter().find(|p| Path::new(p).exists()).ok_or_else(|| ConfErr::new(format!("Config file not found, tried {}", CONFIG_PATHS.join(", "))))?
+        };
+
+        let text = read_to_string(path).map_err(|e| ConfErr::new(format!("Failed to read config file {}: {}", path, e)))?;
+        let mut conf = Config::default();
+        conf.merge(File::from_str(&text, FileFormat::Toml)).map_err(|e| ConfErr::new(format!("Failed to parse config file {}: {}", path, e)))?;
+
+        // Only secrets in the file itself matter here, so this happens before the environment is merged in
+        let mut problems = Vec::new();
+        if let Err(problem) = check_secret_permissions(path, &conf) {
+            problems.push(problem);
+        }
+
+        conf.merge(Environment::with_prefix("POODLE").separator("__")).map_err(|e| ConfErr::new(format!("Failed to read environment: {}", e)))?;
+
+        let mut loader = Loader {
+            conf,
+            problems,
+            discord
+        };
+        let (mut conf, accounts) = Self::parse(&mut loader);
+        conf.path = path.to_string();
+
+        if loader.problems.is_empty() {
+            Ok((conf, accounts))
+        } else {
+            Err(ConfErr {
+                problems: loader.problems
+            })
+        }
+    }
+
+    fn parse(loader: &mut Loader) -> (Self, HashMap<String, MoodleAuthConf>) {
+        let account_names = loader.names("accounts");
+        let webhook_names = loader.names("webhooks");
+
+        let mut accounts = HashMap::new();
+        match (loader.secret("user"), loader.secret("pass")) {
+            (Some(user), Some(pass)) => {
+                accounts.insert("default".to_string(), MoodleAuthConf::ShibbolethUser(user, pass));
+            },
+            (Some(_), None) => loader.problem("Key \"pass\" missing from config".to_string()),
+            (None, Some(_)) => loader.problem("Key \"user\" missing from config".to_string()),
+            (None, None) => ()
+        }
+        for name in &account_names {
+            let user = loader.secret(&format!("accounts.{}.user", name));
+            let pass = loader.secret(&format!("accounts.{}.pass", name));
+            match (user, pass) {
+                (Some(user), Some(pass)) => {
+                    accounts.insert(name.clone(), MoodleAuthConf::ShibbolethUser(user, pass));
+                },
+                (None, _) => loader.problem(format!("Key \"user\" missing from account {} in config", name)),
+                (_, None) => loader.problem(format!("Key \"pass\" missing from account {} in config", name))
+            }
+        }
+
+        let default_account = loader.str("default_account").unwrap_or_else(|| "default".to_string());
+        if !accounts.contains_key(&default_account) {
+            loader.problem(format!("Default Moodle account \"{}\" is not configured", default_account));
+        }
+
+        let mut guild_accounts = HashMap::new();
+        for (guild, account) in loader.conf.get_table("guild_accounts").unwrap_or_default() {
+            match (guild.parse::<u64>(), account.into_str()) {
+                (Ok(guild), Ok(account)) => {
+                    if !accounts.contains_key(&account) && !account_names.contains(&account) {
+                        loader.problem(format!("Guild {} is assigned to unknown Moodle account \"{}\"", guild, account));
+                    }
+                    guild_accounts.insert(GuildId(guild), account);
+                },
+                (Err(_), _) => loader.problem(format!("Expected guild ids as keys of guild_accounts in config, found {}", guild)),
+                (_, Err(_)) => loader.problem(format!("Expected an account name for guild {} in guild_accounts in config", guild))
+            }
+        }
+
+        let mut webhooks = Vec::new();
+        for name in &webhook_names {
+            let url = loader.str(&format!("webhooks.{}.url", name));
+            match url.as_deref().map(reqwest::Url::parse) {
+                Some(Ok(url)) if url.scheme() == "http" || url.scheme() == "https" => (),
+                Some(_) => loader.problem(format!("Expected an http or https URL for url of webhook {} in config, found \"{}\"", name, url.as_deref().unwrap_or(""))),
+                None => loader.problem(format!("Key \"url\" missing from webhook {} in config", name))
+            }
+            webhooks.push(WebhookConf {
+                name: name.clone(),
+                url: url.unwrap_or_default(),
+                secret: loader.secret(&format!("webhooks.{}.secret", name)),
+                courses: loader.courses(&format!("webhooks.{}.courses", name))
+            });
+        }
+
+        let email = if loader.conf.get_table("email").is_ok() {
+            let from = loader.required_str("email.from");
+            let to = loader.list("email.to").unwrap_or_else(|| {
+                loader.problem("Key \"email.to\" missing from config".to_string());
+                Vec::new()
+            });
+            let mut addresses = to.iter().map(|a| ("email.to", a)).collect::<Vec<_>>();
+            if !from.is_empty() {
+                addresses.push(("email.from", &from));
+            }
+            for (key, address) in addresses {
+                if address.parse::<Mailbox>().is_err() {
+                    loader.problem(format!("Expected an email address in {} in config, found \"{}\"", key, address));
+                }
+            }
+
+            let email = EmailConf {
+                server: loader.required_str("email.server"),
+                port: loader.int_in("email.port", 1, u16::MAX as i64).map(|p| p as u16),
+                security: loader.choice("email.security", "starttls", &[("tls", SmtpSecurity::Tls), ("starttls", SmtpSecurity::StartTls), ("none", SmtpSecurity::None)]),
+                user: loader.secret("email.user"),
+                pass: loader.secret("email.pass"),
+                from,
+                to,
+                mode: match loader.int("email.digest_hour") {
+                    Some(hour) if (0..24).contains(&hour) => EmailMode::Daily(hour as u32),
+                    Some(hour) => {
+                        loader.problem(format!("Expected an hour between 0 and 23 for email.digest_hour in config, found {}", hour));
+                        EmailMode::Immediate
+                    },
+                    None => EmailMode::Immediate
+                },
+                courses: loader.courses("email.courses")
+            };
+            if let Err(e) = EmailNotifier::new(email.clone()) {
+                loader.problem(format!("Invalid SMTP server \"{}\" for email.server in config: {}", email.server, e));
+            }
+            Some(email)
+        } else {
+            None
+        };
+
+        let discord_token = match loader.secret("token") {
+            Some(token) => token,
+            None if loader.discord => {
+                loader.problem("Key \"token\" missing from config".to_string());
+                String::new()
+            },
+            None => String::new()
+        };
+        let discord_client_id = if loader.discord { loader.required_str("client") } else { loader.str("client").unwrap_or_default() };
+        // The top level channel and courses are the channel named "default"
+        let mut channels = Vec::new();
+        if loader.str("channel").is_some() || loader.list("courses").is_some() {
+            channels.push(loader.channel("default", ""));
+        }
+        for name in loader.names("channels") {
+            let channel = loader.channel(&name, &format!("channels.{}.", name));
+            if channels.iter().any(|c| c.channel == channel.channel) {
+                loader.problem(format!("Discord channel {} is configured more than once", channel.channel));
+            }
+            if let Some(account) = &channel.account {
+                if !accounts.contains_key(account) && !account_names.contains(account) {
+                    loader.problem(format!("Channel {} uses unknown Moodle account \"{}\"", name, account));
+                }
+            }
+            channels.push(channel);
+        }
+
+        let responses = loader.list("responses").unwrap_or_default();
+        if responses.is_empty() && loader.discord {
+            loader.problem("Key \"responses\" must list at least one response in config".to_string());
+        }
+
+        // The admin user may always use every command
+        let admin_user_id = loader.int_in("admin_user", 1, i64::MAX).map(|id| UserId(id as u64));
+        let mut permissions = Permissions {
+            all: loader.rule("permissions."),
+            commands: HashMap::new()
+        };
+        permissions.all.users.extend(admin_user_id);
+        for name in loader.names("permissions").into_iter().filter(|n| n != "users" && n != "roles") {
+            if !COMMANDS.contains(&name.as_str()) {
+                let names = COMMANDS.iter().map(|c| format!("\"{}\"", c)).collect::<Vec<_>>().join(", ");
+                loader.problem(format!("Expected one of {} as command in permissions in config, found \"{}\"", names, name));
+                continue;
+            }
+            let rule = loader.rule(&format!("permissions.{}.", name));
+            permissions.commands.insert(name, rule);
+        }
+
+        let conf = Conf {
+            path: String::new(),
+            discord_token,
+            discord_client_id,
+            channels,
+            responses,
+            admin_channel_id: loader.int_in("admin_channel", 1, i64::MAX).map(|id| (id as u64).into()),
+            admin_user_id,
+            alert_threshold: loader.int_in("alert_threshold", 1, u32::MAX as i64).unwrap_or(3) as u32,
+            default_account,
+            guild_accounts,
+            file_tracking: loader.choice("track_files", "off", &[("off", FileTracking::Off), ("revision", FileTracking::Revision), ("hash", FileTracking::Hash)]),
+            mirror_dir: loader.str("mirror_dir"),
+            http_listen: loader.str("http_listen").filter(|addr| {
+                let valid = addr.to_socket_addrs().map(|mut a| a.next().is_some()).unwrap_or(false);
+                if !valid {
+                    loader.problem(format!("Expected an address like 127.0.0.1:8080 for http_listen in config, found \"{}\"", addr));
+                }
+                valid
+            }),
+            webhooks,
+            email,
+            log_level: loader.str("log_level").filter(|level| {
+                let valid = logging::valid_level(level);
+                if !valid {
+                    loader.problem(format!("Expected a level like \"info\" or \"debug\" for log_level in config, found \"{}\"", level));
+                }
+                valid
+            }).unwrap_or_else(|| "info".to_string()),
+            log_format: loader.choice("log_format", "text", &[("text", LogFormat::Text), ("json", LogFormat::Json)]),
+            permissions
+        };
+
+        (conf, accounts)
+    }
+}
+
+impl Conf {
+    pub fn channel(&self, channel: ChannelId) -> Option<&ChannelConf> {
+        self.channels.iter().find(|c| c.channel == channel)
+    }
+
+    // The channel's own response set if it has one
+    pub fn responses(&self, channel: ChannelId) -> &[String] {
+        self.channel(channel).and_then(|c| c.responses.as_deref()).unwrap_or(&self.responses)
+    }
+
+    // The channel's configured account, then the guild's, then the default one
+    pub fn account_for(&self, channel: ChannelId, guild: Option<GuildId>) -> String {
+        self.channel(channel).and_then(|c| c.account.clone())
+            .or_else(|| guild.and_then(|g| self.guild_accounts.get(&g).cloned()))
+            .unwrap_or_else(|| self.default_account.clone())
+    }
+}
+
+impl Conf {
+    // The keys that differ from the new config but only take effect with a restart, as what uses them is set up once at startup
+    pub fn restart_changes(&self, new: &Conf) -> Vec<String> {
+        let mut keys = Vec::new();
+        let mut check = |key: &str, changed: bool| if changed {
+            keys.push(key.to_string());
+        };
+        check("token", self.discord_token != new.discord_token);
+        check("alert_threshold", self.alert_threshold != new.alert_threshold);
+        check("track_files", self.file_tracking != new.file_tracking);
+        check("mirror_dir", self.mirror_dir != new.mirror_dir);
+        check("http_listen", self.http_listen != new.http_listen);
+        check("webhooks", self.webhooks != new.webhooks);
+        check("email", self.email != new.email);
+        check("log_level", self.log_level != new.log_level);
+        check("log_format", self.log_format != new.log_format);
+
+        // Channels watching courses keep the account they started with
+        for channel in &new.channels {
+            if self.channel(channel.channel).map(|c| c.account != channel.account).unwrap_or(false) {
+                keys.push(format!("channels.{}.account", channel.name));
+            }
+        }
+        keys
+    }
+}
+
+// A channel that watches its courses from startup on
+#[derive(Clone, Debug)]
+pub struct ChannelConf {
+    pub name: String,
+    pub channel: ChannelId,
+    pub courses: Vec<u32>,
+    pub account: Option<String>,
+    pub responses: Option<Vec<String>>,
+    // Change kinds posted to the channel, all of them if None
+    pub notify: Option<Vec<String>>
+}
+
+impl ChannelConf {
+    pub fn wants(&self, change: &MoodleChange) -> bool {
+        self.notify.as_ref().map(|kinds| kinds.iter().any(|k| k == change.kind())).unwrap_or(true)
+    }
+}
+
+// The current config, replaced as a whole when it is reloaded
+pub struct SharedConf {
+    conf: RwLock<Arc<Conf>>
+}
+
+impl SharedConf {
+    pub fn new(conf: Conf) -> Self {
+        Self {
+            conf: RwLock::new(Arc::new(conf))
+        }
+    }
+
+    pub fn get(&self) -> Arc<Conf> {
+        self.conf.read().unwrap().clone()
+    }
+
+    pub fn set(&self, conf: Conf) -> Arc<Conf> {
+        let conf = Arc::new(conf);
+        *self.conf.write().unwrap() = conf.clone();
+        conf
+    }
+}
+
+// All problems found in the config, reported together so they can be fixed in one go
+#[derive(Debug)]
+pub struct ConfErr {
+    pub problems: Vec<String>
+}
+
+impl ConfErr {
+    fn new(problem: String) -> Self {
+        Self {
+            problems: vec![problem]
+        }
+    }
+}
+
+impl fmt::Display for ConfErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfErr {}
+
+// Collects problems instead of stopping at the first one
+struct Loader {
+    conf: Config,
+    problems: Vec<String>,
+    // Whether the keys only the bot needs are required
+    discord: bool
+}
+
+impl Loader {
+    fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    fn names(&self, table: &str) -> Vec<String> {
+        let mut names = self.conf.get_table(table).map(|t| t.keys().cloned().collect::<Vec<_>>()).unwrap_or_default();
+        names.sort();
+        names
+    }
+
+    fn secret(&mut self, key: &str) -> Option<String> {
+        match get_secret(&self.conf, key) {
+            Ok(secret) => secret,
+            Err(problem) => {
+                self.problem(problem);
+                None
+            }
+        }
+    }
+
+    fn str(&self, key: &str) -> Option<String> {
+        self.conf.get_str(key).ok()
+    }
+
+    fn required_str(&mut self, key: &str) -> String {
+        self.str(key).unwrap_or_else(|| {
+            self.problem(format!("Key \"{}\" missing from config", key));
+            String::new()
+        })
+    }
+
+    fn int(&mut self, key: &str) -> Option<i64> {
+        match self.conf.get_int(key) {
+            Ok(value) => Some(value),
+            Err(ConfigError::NotFound(_)) => None,
+            Err(e) => {
+                self.problem(format!("Expected a number for {} in config: {}", key, e));
+                None
+            }
+        }
+    }
+
+    fn int_in(&mut self, key: &str, min: i64, max: i64) -> Option<i64> {
+        self.int(key).filter(|value| {
+            let valid = (min..=max).contains(value);
+            if !valid {
+                self.problem(format!("Expected a number between {} and {} for {} in config, found {}", min, max, key, value));
+            }
+            valid
+        })
+    }
+
+    // Arrays in the file, comma separated in the environment
+    fn list(&mut self, key: &str) -> Option<Vec<String>> {
+        match self.conf.get_array(key) {
+            Ok(values) => Some(values.into_iter().filter_map(|v| match v.into_str() {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    self.problem(format!("Expected strings in {} in config: {}", key, e));
+                    None
+                }
+            }).collect()),
+            Err(_) => self.str(key).map(|s| s.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+        }
+    }
+
+    // Reads channel, courses, notify, responses and account below prefix, where the top level (empty prefix) has neither its
+    // own responses nor an account
+    fn channel(&mut self, name: &str, prefix: &str) -> ChannelConf {
+        let key = |k: &str| format!("{}{}", prefix, k);
+
+        // Discord ids are positive, so a negative one isn't wrapped around into some other channel
+        let channel = match self.str(&key("channel")) {
+            Some(_) => self.int_in(&key("channel"), 1, i64::MAX).unwrap_or(0),
+            None => {
+                self.problem(format!("Key \"{}\" missing from config", key("channel")));
+                0
+            }
+        };
+        if self.list(&key("courses")).is_none() {
+            self.problem(format!("Key \"{}\" missing from config", key("courses")));
+        }
+
+        let notify = self.list(&key("notify"));
+        for kind in notify.iter().flatten().filter(|k| !CHANGE_KINDS.contains(&k.as_str())) {
+            let names = CHANGE_KINDS.iter().map(|k| format!("\"{}\"", k)).collect::<Vec<_>>().join(", ");
+            self.problems.push(format!("Expected one of {} in {} in config, found \"{}\"", names, key("notify"), kind));
+        }
+
+        let (responses, account) = if prefix.is_empty() {
+            (None, None)
+        } else {
+            (self.list(&key("responses")), self.str(&key("account")))
+        };
+        if responses.as_ref().map(|r| r.is_empty()).unwrap_or(false) {
+            self.problem(format!("Key \"{}\" must list at least one response in config", key("responses")));
+        }
+
+        ChannelConf {
+            name: name.to_string(),
+            channel: (channel as u64).into(),
+            courses: self.courses(&key("courses")),
+            account,
+            responses,
+            notify
+        }
+    }
+
+    // Reads the users and roles below prefix
+    fn rule(&mut self, prefix: &str) -> Rule {
+        Rule {
+            users: self.ids(&format!("{}users", prefix)).into_iter().map(UserId).collect(),
+            roles: self.ids(&format!("{}roles", prefix)).into_iter().map(RoleId).collect()
+        }
+    }
+
+    fn ids(&mut self, key: &str) -> Vec<u64> {
+        self.list(key).unwrap_or_default().into_iter().filter_map(|id| match id.parse() {
+            Ok(id) => Some(id),
+            Err(_) => {
+                self.problem(format!("Expected numeric Discord ids in {} in config, found \"{}\"", key, id));
+                None
+            }
+        }).collect()
+    }
+
+    fn courses(&mut self, key: &str) -> Vec<u32> {
+        self.list(key).unwrap_or_default().into_iter().filter_map(|id| match id.parse() {
+            Ok(id) => Some(id),
+            Err(_) => {
+                self.problem(format!("Expected numeric course ids in {} in config, found \"{}\"", key, id));
+                None
+            }
+        }).collect()
+    }
+
+    fn choice<T: Copy>(&mut self, key: &str, default: &str, options: &[(&str, T)]) -> T {
+        let value = self.str(key).unwrap_or_else(|| default.to_string());
+        match options.iter().find(|(name, _)| *name == value) {
+            Some((_, option)) => *option,
+            None => {
+                let names = options.iter().map(|(name, _)| format!("\"{}\"", name)).collect::<Vec<_>>().join(", ");
+                self.problem(format!("Expected one of {} for {} in config, found \"{}\"", names, key, value));
+                options.iter().find(|(name, _)| *name == default).map(|(_, o)| *o).unwrap_or(options[0].1)
+            }
+        }
+    }
+}
+
+// Looks up POODLE_<KEY> (with __ between nested keys, like every other override), then the file named by POODLE_<KEY>_FILE, then
+// systemd's $CREDENTIALS_DIRECTORY/<key>, and only then falls back to the config file
+fn get_secret(conf: &Config, key: &str) -> Result<Option<String>, String> {
+    let var = format!("POODLE_{}", key.split('.')
+        .map(|k| k.to_uppercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_"))
+        .collect::<Vec<_>>()
+        .join("__"));
+
+    if let Ok(value) = env::var(&var) {
+        return Ok(Some(value));
+    }
+
+    if let Ok(path) = env::var(format!("{}_FILE", var)) {
+        return read_secret_file(&path).map(Some);
+    }
+
+    if let Ok(dir) = env::var("CREDENTIALS_DIRECTORY") {
+        let path = Path::new(&dir).join(key);
+        if path.exists() {
+            return read_secret_file(&path).map(Some);
+        }
+    }
+
+    Ok(conf.get_str(key).ok().filter(|s| !s.is_empty()))
+}
+
+fn read_secret_file<P: AsRef<Path>>(path: P) -> Result<String, String> {
+    let path = path.as_ref();
+    read_to_string(path).map(|s| s.trim_end_matches(&['\r', '\n'][..]).to_string()).map_err(|e| format!("Failed to read secret file {}: {}", path.display(), e))
+}
+
+fn check_secret_permissions(path: &str, conf: &Config) -> Result<(), String> {
+    let names = |table: &str| conf.get_table(table).map(|t| t.keys().cloned().collect::<Vec<_>>()).unwrap_or_default();
+
+    let mut keys = SECRET_KEYS.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+    for name in names("accounts") {
+        keys.push(format!("accounts.{}.user", name));
+        keys.push(format!("accounts.{}.pass", name));
+    }
+    for name in names("webhooks") {
+        keys.push(format!("webhooks.{}.secret", name));
+    }
+
+    let has_secrets = keys.iter().any(|key| conf.get_str(key).map(|s| !s.is_empty()).unwrap_or(false));
+    let mode = metadata(path).map_err(|e| format!("Failed to read config file metadata: {}", e))?.permissions().mode();
+
+    if has_secrets && mode & 0o004 != 0 {
+        return Err(format!("Config file {} contains secrets but is world-readable, restrict it (chmod o-r {}) or move the secrets to environment variables", path, path));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn parse_str(toml: &str) -> (Conf, Vec<String>) {
+    parse_str_with(toml, true)
+}
+
+#[cfg(test)]
+fn parse_str_with(toml: &str, discord: bool) -> (Conf, Vec<String>) {
+    let mut conf = Config::default();
+    conf.merge(File::from_str(toml, FileFormat::Toml)).unwrap();
+
+    let mut loader = Loader {
+        conf,
+        problems: Vec::new(),
+        discord
+    };
+    let (conf, _) = Conf::parse(&mut loader);
+    (conf, loader.problems)
+}
+
+#[test]
+fn test_conf_reports_all_problems() {
+    let (_, problems) = parse_str("client = \"1\"\nchannel = 2\ncourses = [\"x\"]\nresponses = []\ntrack_files = \"always\"\n[accounts.groupb]\nuser = \"ab12cde\"\n");
+
+    assert_eq!(problems, vec![
+        "Key \"pass\" missing from account groupb in config",
+        "Default Moodle account \"default\" is not configured",
+        "Key \"token\" missing from config",
+        "Expected numeric course ids in courses in config, found \"x\"",
+        "Key \"responses\" must list at least one response in config",
+        "Expected one of \"off\", \"revision\", \"hash\" for track_files in config, found \"always\""
+    ]);
+}
+
+#[test]
+fn test_conf_channels() {
+    let (conf, problems) = parse_str("token = \"t\"\nclient = \"1\"\nuser = \"u\"\npass = \"p\"\nresponses = [\"Hi\"]\nchannel = 2\ncourses = [\"10\"]\n\
+        [channels.other]\nchannel = 3\ncourses = [\"10\", \"11\"]\nresponses = [\"Moin\"]\nnotify = [\"uploaded\"]\n\
+        [channels.broken]\nchannel = 3\ncourses = []\nnotify = [\"deleted\"]\naccount = \"nobody\"\n");
+
+    assert_eq!(problems, vec![
+        "Expected one of \"uploaded\", \"available\", \"text_changed\", \"folder_changed\", \"file_updated\" in channels.broken.notify in config, found \"deleted\"",
+        "Channel broken uses unknown Moodle account \"nobody\"",
+        "Discord channel 3 is configured more than once"
+    ]);
+
+    let names = conf.channels.iter().map(|c| c.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["default", "broken", "other"]);
+    assert_eq!(conf.responses(ChannelId(2)), ["Hi"]);
+    assert_eq!(conf.responses(ChannelId(4)), ["Hi"]);
+    assert_eq!(conf.channel(ChannelId(2)).unwrap().courses, vec![10]);
+
+    let other = &conf.channels[2];
+    assert_eq!(other.courses, vec![10, 11]);
+    assert!(other.wants(&MoodleChange::Uploaded { kind: "File".to_string(), name: "Sheet 1".to_string() }));
+    assert!(!other.wants(&MoodleChange::FileUpdated { name: "Sheet 1".to_string() }));
+}
+
+#[test]
+fn test_conf_account_for() {
+    let (conf, problems) = parse_str("token = \"t\"\nclient = \"1\"\nuser = \"u\"\npass = \"p\"\nresponses = [\"Hi\"]\nchannel = 2\ncourses = []\n\
+        [accounts.groupb]\nuser = \"b\"\npass = \"p\"\n[accounts.groupc]\nuser = \"c\"\npass = \"p\"\n\
+        [channels.other]\nchannel = 3\ncourses = []\naccount = \"groupc\"\n[guild_accounts]\n\"7\" = \"groupb\"\n");
+
+    assert!(problems.is_empty(), "{:?}", problems);
+    assert_eq!(conf.account_for(ChannelId(3), Some(GuildId(7))), "groupc");
+    assert_eq!(conf.account_for(ChannelId(2), Some(GuildId(7))), "groupb");
+    assert_eq!(conf.account_for(ChannelId(4), Some(GuildId(7))), "groupb");
+    assert_eq!(conf.account_for(ChannelId(4), Some(GuildId(8))), conf.default_account);
+    assert_eq!(conf.account_for(ChannelId(4), None), conf.default_account);
+}
+
+#[test]
+fn test_conf_addresses() {
+    let (conf, problems) = parse_str("http_listen = \"8080\"\n[webhooks.chat]\nurl = \"ftp://example.com\"\n\
+        [email]\nserver = \"smtp.example.com\"\nfrom = \"poodle@example.com\"\nto = [\"team@example.com\", \"team\"]\n");
+
+    assert!(problems.contains(&"Expected an http or https URL for url of webhook chat in config, found \"ftp://example.com\"".to_string()));
+    assert!(problems.contains(&"Expected an email address in email.to in config, found \"team\"".to_string()));
+    assert!(!problems.iter().any(|p| p.contains("team@example.com") || p.contains("email.from")));
+    assert!(problems.contains(&"Expected an address like 127.0.0.1:8080 for http_listen in config, found \"8080\"".to_string()));
+    assert_eq!(conf.http_listen, None);
+}
+
+#[test]
+fn test_conf_permissions() {
+    let (conf, problems) = parse_str("admin_user = 1\n[permissions]\nusers = [\"2\"]\nroles = [\"10\"]\n[permissions.send]\nroles = [\"20\"]\n\
+        [permissions.export]\nusers = [\"3\"]\n[permissions.help]\nusers = [\"3\"]\n[permissions.watch]\nusers = [\"x\"]\n");
+
+    assert!(problems.contains(&"Expected one of \"watch\", \"unwatch\", \"timer\", \"makegroups\", \"account\", \"export\", \"status\", \"send\" as command in permissions in config, found \"help\"".to_string()));
+    assert!(problems.contains(&"Expected numeric Discord ids in permissions.watch.users in config, found \"x\"".to_string()));
+
+    assert_eq!(conf.permissions.all.users, vec![UserId(2), UserId(1)]);
+    assert_eq!(conf.permissions.all.roles, vec![RoleId(10)]);
+    assert_eq!(conf.permissions.commands["send"].roles, vec![RoleId(20)]);
+    assert_eq!(conf.permissions.commands["export"].users, vec![UserId(3)]);
+    assert!(!conf.permissions.commands.contains_key("help"));
+}
+
+#[test]
+fn test_conf_restart_changes() {
+    let base = "token = \"t\"\nclient = \"1\"\nuser = \"u\"\npass = \"p\"\nresponses = [\"Hi\"]\n[channels.other]\nchannel = 3\ncourses = [\"10\"]\n";
+    let (old, _) = parse_str(base);
+    let (new, _) = parse_str(&format!("alert_threshold = 5\ntrack_files = \"hash\"\n{}account = \"default\"\n", base));
+    let (same, _) = parse_str(&format!("responses = [\"Moin\"]\n{}", base.replace("responses = [\"Hi\"]\n", "")));
+
+    assert_eq!(old.restart_changes(&new), vec!["alert_threshold", "track_files", "channels.other.account"]);
+    assert!(old.restart_changes(&same).is_empty());
+}
+
+#[test]
+fn test_conf_ranges() {
+    let (conf, problems) = parse_str("alert_threshold = -1\nlog_level = \"loud\"\n[email]\nserver = \"smtp.example.com\"\nport = 70000\nfrom = \"poodle@example.com\"\nto = [\"team@example.com\"]\n");
+
+    assert!(problems.contains(&"Expected a number between 1 and 4294967295 for alert_threshold in config, found -1".to_string()));
+    assert!(problems.contains(&"Expected a number between 1 and 65535 for email.port in config, found 70000".to_string()));
+    assert!(problems.contains(&"Expected a level like \"info\" or \"debug\" for log_level in config, found \"loud\"".to_string()));
+    assert_eq!(conf.alert_threshold, 3);
+    assert_eq!(conf.email.unwrap().port, None);
+
+    let (_, problems) = parse_str("admin_user = -1\nadmin_channel = 0\nchannel = -2\ncourses = []\n");
+    assert!(problems.contains(&"Expected a number between 1 and 9223372036854775807 for admin_user in config, found -1".to_string()));
+    assert!(problems.contains(&"Expected a number between 1 and 9223372036854775807 for admin_channel in config, found 0".to_string()));
+    assert!(problems.contains(&"Expected a number between 1 and 9223372036854775807 for channel in config, found -2".to_string()));
+    assert!(!problems.iter().any(|p| p.contains("\"channel\" missing")));
+
+    let (conf, problems) = parse_str("alert_threshold = 5\nlog_level = \"debug\"\n");
+    assert!(!problems.iter().any(|p| p.contains("alert_threshold") || p.contains("log_level")));
+    assert_eq!((conf.alert_threshold, conf.log_level.as_str()), (5, "debug"));
+}
+
+#[test]
+fn test_conf_cli() {
+    let toml = "user = \"u\"\npass = \"p\"\ntrack_files = \"revision\"\n";
+
+    let (_, problems) = parse_str(toml);
+    assert_eq!(problems, vec!["Key \"token\" missing from config", "Key \"client\" missing from config", "Key \"responses\" must list at least one response in config"]);
+
+    let (conf, problems) = parse_str_with(toml, false);
+    assert!(problems.is_empty());
+    assert_eq!(conf.file_tracking, FileTracking::Revision);
+}
+
+// Uses keys of its own, as the environment is shared with the tests running alongside
+#[test]
+fn test_conf_secrets() {
+    use std::fs::{create_dir_all, remove_dir_all, set_permissions, write, Permissions};
+
+    let dir = env::temp_dir().join(format!("poodle-secrets-{}", std::process::id()));
+    let credentials = dir.join("credentials");
+    create_dir_all(&credentials).unwrap();
+
+    let mut conf = Config::default();
+    conf.merge(File::from_str("[accounts.envtest]\npass = \"from config\"\n", FileFormat::Toml)).unwrap();
+    let key = "accounts.envtest.pass";
+    assert_eq!(get_secret(&conf, key), Ok(Some("from config".to_string())));
+
+    write(credentials.join(key), "from credentials\n").unwrap();
+    env::set_var("CREDENTIALS_DIRECTORY", &credentials);
+    assert_eq!(get_secret(&conf, key), Ok(Some("from credentials".to_string())));
+
+    write(dir.join("pass"), "from file\r\n\n").unwrap();
+    env::set_var("POODLE_ACCOUNTS__ENVTEST__PASS_FILE", dir.join("pass"));
+    assert_eq!(get_secret(&conf, key), Ok(Some("from file".to_string())));
+
+    // Single underscores don't nest, as with the other overrides
+    env::set_var("POODLE_ACCOUNTS_ENVTEST_PASS", "from env");
+    assert_eq!(get_secret(&conf, key), Ok(Some("from file".to_string())));
+    env::remove_var("POODLE_ACCOUNTS_ENVTEST_PASS");
+
+    env::set_var("POODLE_ACCOUNTS__ENVTEST__PASS", "from env");
+    assert_eq!(get_secret(&conf, key), Ok(Some("from env".to_string())));
+
+    env::remove_var("POODLE_ACCOUNTS__ENVTEST__PASS");
+    env::set_var("POODLE_ACCOUNTS__ENVTEST__PASS_FILE", dir.join("missing"));
+    assert!(get_secret(&conf, key).unwrap_err().starts_with("Failed to read secret file"));
+    env::remove_var("POODLE_ACCOUNTS__ENVTEST__PASS_FILE");
+    env::remove_var("CREDENTIALS_DIRECTORY");
+
+    let path = dir.join("poodle.toml");
+    let path = path.to_str().unwrap();
+    write(path, "pass = \"hunter2\"\n").unwrap();
+    let mut conf = Config::default();
+    conf.merge(File::from_str("pass = \"hunter2\"\n", FileFormat::Toml)).unwrap();
+
+    set_permissions(path, Permissions::from_mode(0o644)).unwrap();
+    assert!(check_secret_permissions(path, &conf).unwrap_err().contains("is world-readable"));
+    set_permissions(path, Permissions::from_mode(0o600)).unwrap();
+    assert_eq!(check_secret_permissions(path, &conf), Ok(()));
+    set_permissions(path, Permissions::from_mode(0o644)).unwrap();
+    assert_eq!(check_secret_permissions(path, &Config::default()), Ok(()));
+
+    remove_dir_all(&dir).unwrap();
+}
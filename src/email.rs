@@ -0,0 +1,233 @@
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use lettre::transport::smtp::authentication::Credentials;
+
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+use tracing::error;
+
+use crate::notify::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmtpSecurity {
+    Tls,
+    StartTls,
+    None
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmailMode {
+    Immediate,
+    // Collects all changes and sends them at the given hour (UTC)
+    Daily(u32)
+}
+
+#[derive(Clone, PartialEq)]
+pub struct EmailConf {
+    pub server: String,
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    pub mode: EmailMode,
+    pub courses: Vec<u32>
+}
+
+impl fmt::Debug for EmailConf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EmailConf")
+            .field("server", &self.server)
+            .field("port", &self.port)
+            .field("security", &self.security)
+            .field("user", &self.user)
+            .field("pass", &self.pass.as_ref().map(|_| "<redacted>"))
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .field("mode", &self.mode)
+            .field("courses", &self.courses)
+            .finish()
+    }
+}
+
+#[derive(Clone)]
+pub struct EmailNotifier {
+    conf: Arc<EmailConf>,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    pending: Arc<Mutex<Vec<ChangeEvent>>>
+}
+
+impl EmailNotifier {
+    pub fn new(conf: EmailConf) -> Result<Self, lettre::transport::smtp::Error> {
+        let mut builder = match conf.security {
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&conf.server)?,
+            SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&conf.server)?,
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&conf.server)
+        };
+        if let Some(port) = conf.port {
+            builder = builder.port(port);
+        }
+        if let (Some(user), Some(pass)) = (&conf.user, &conf.pass) {
+            builder = builder.credentials(Credentials::new(user.clone(), pass.clone()));
+        }
+
+        Ok(Self {
+            conf: Arc::new(conf),
+            transport: builder.build(),
+            pending: Arc::new(Mutex::new(Vec::new()))
+        })
+    }
+
+    // Sends the collected changes once a day, does nothing for immediate mails
+    pub fn start_digest(&self) {
+        if let EmailMode::Daily(hour) = self.conf.mode {
+            let notifier = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    sleep(until_hour(hour, Utc::now().timestamp())).await;
+                    if let Err(e) = notifier.flush().await {
+                        error!("Failed to send email digest: {}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    async fn flush(&self) -> Result<(), NotifyErr> {
+        let events = std::mem::take(&mut *self.pending.lock().await);
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let body = events.iter().map(|e| format!("{}\n{}\n{}\n", e.title(), e.course_url, e.text())).collect::<Vec<_>>().join("\n");
+        let res = self.send(format!("Poodle digest: {} course updates", events.len()), body).await;
+
+        // Keep the changes for the next digest instead of losing them
+        if res.is_err() {
+            let mut pending = self.pending.lock().await;
+            let newer = std::mem::replace(&mut *pending, events);
+            pending.extend(newer);
+        }
+
+        res
+    }
+
+    async fn send(&self, subject: String, body: String) -> Result<(), NotifyErr> {
+        let mut message = Message::builder().from(self.conf.from.parse()?).subject(subject);
+        for to in &self.conf.to {
+            message = message.to(to.parse()?);
+        }
+
+        self.transport.send(message.body(body)?).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyErr> {
+        if !self.conf.courses.is_empty() && !self.conf.courses.contains(&event.course_id) {
+            return Ok(());
+        }
+
+        match self.conf.mode {
+            EmailMode::Immediate => self.send(event.title(), format!("{}\n{}", event.course_url, event.text())).await,
+            EmailMode::Daily(_) => {
+                self.pending.lock().await.push(event.clone());
+                Ok(())
+            }
+        }
+    }
+}
+
+fn until_hour(hour: u32, now: i64) -> Duration {
+    let wait = (hour as i64 * 3600 - now.rem_euclid(86400)).rem_euclid(86400);
+    Duration::from_secs(if wait == 0 { 86400 } else { wait as u64 })
+}
+
+#[test]
+fn test_email_until_hour() {
+    assert_eq!(until_hour(18, 17 * 3600), Duration::from_secs(3600));
+    assert_eq!(until_hour(6, 86400 + 7 * 3600), Duration::from_secs(23 * 3600));
+    assert_eq!(until_hour(0, 0), Duration::from_secs(86400));
+}
+
+#[cfg(test)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+// Speaks just enough SMTP to accept one mail and returns everything sent after DATA
+#[cfg(test)]
+async fn smtp_stand_in(listener: tokio::net::TcpListener) -> String {
+    let (stream, _) = listener.accept().await.unwrap();
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let mut data = String::new();
+    let mut in_data = false;
+
+    write.write_all(b"220 localhost\r\n").await.unwrap();
+    while let Some(line) = lines.next_line().await.unwrap() {
+        if in_data {
+            if line == "." {
+                in_data = false;
+                write.write_all(b"250 OK\r\n").await.unwrap();
+            } else {
+                data.push_str(&line);
+                data.push('\n');
+            }
+            continue;
+        }
+
+        let reply: &[u8] = match line.split(' ').next().unwrap_or("").to_uppercase().as_str() {
+            "EHLO" | "HELO" => b"250 localhost\r\n",
+            "DATA" => {
+                in_data = true;
+                b"354 Go ahead\r\n"
+            },
+            "QUIT" => {
+                write.write_all(b"221 Bye\r\n").await.unwrap();
+                break;
+            },
+            _ => b"250 OK\r\n"
+        };
+        write.write_all(reply).await.unwrap();
+    }
+
+    data
+}
+
+#[tokio::test]
+async fn test_email_immediate() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = tokio::spawn(smtp_stand_in(listener));
+
+    let notifier = EmailNotifier::new(EmailConf {
+        server: "127.0.0.1".to_string(),
+        port: Some(port),
+        security: SmtpSecurity::None,
+        user: None,
+        pass: None,
+        from: "poodle@example.com".to_string(),
+        to: vec!["team@example.com".to_string()],
+        mode: EmailMode::Immediate,
+        courses: Vec::new()
+    }).unwrap();
+
+    notifier.notify(&ChangeEvent::example()).await.unwrap();
+    drop(notifier);
+
+    let data = server.await.unwrap();
+    assert!(data.contains("Subject: Update in course Test"));
+    assert!(data.contains("Updated: \"Sheet 1\""));
+}
@@ -0,0 +1,139 @@
+use serde::Serialize;
+
+use crate::moodle::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Markdown
+}
+
+impl ExportFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(ExportFormat::Json),
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            _ => None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Markdown => "md"
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CourseExport<'a> {
+    id: u32,
+    name: &'a str,
+    url: &'a str,
+    sections: Vec<SectionExport<'a>>
+}
+
+#[derive(Serialize)]
+struct SectionExport<'a> {
+    name: &'a str,
+    activities: Vec<&'a MoodleActivity>
+}
+
+pub fn export(course: &MoodleCourseData, format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Json => serde_json::to_string_pretty(&CourseExport {
+            id: course.id(),
+            name: course.name(),
+            url: course.url(),
+            sections: sections(course)
+        }).expect("Failed to serialise course"),
+        ExportFormat::Markdown => markdown(course)
+    }
+}
+
+// Groups activities by section, keeping the order of the course page
+fn sections(course: &MoodleCourseData) -> Vec<SectionExport<'_>> {
+    let mut sections: Vec<SectionExport> = Vec::new();
+
+    for activity in course.activities() {
+        match sections.iter_mut().find(|s| s.name == activity.section()) {
+            Some(section) => section.activities.push(activity),
+            None => sections.push(SectionExport {
+                name: activity.section(),
+                activities: vec![activity]
+            })
+        }
+    }
+
+    sections
+}
+
+fn markdown(course: &MoodleCourseData) -> String {
+    let mut text = format!("# {}\n\n<{}>\n", escape(course.name()), course.url());
+
+    for section in sections(course) {
+        if !section.name.is_empty() {
+            text.push_str(&format!("\n## {}\n\n", escape(section.name)));
+        } else {
+            text.push('\n');
+        }
+
+        for activity in section.activities {
+            match activity.url() {
+                Some(url) => text.push_str(&format!("- [{}]({}) *({})*\n", escape(activity.name()), url, activity.kind_name())),
+                None => text.push_str(&format!("- {} *({})*\n", escape(activity.name()), activity.kind_name()))
+            }
+
+            for date in activity.dates() {
+                text.push_str(&format!("  - {}\n", escape(date)));
+            }
+            if let Some(restriction) = activity.restriction() {
+                text.push_str(&format!("  - {}\n", escape(restriction)));
+            }
+            for file in activity.files().unwrap_or_default() {
+                text.push_str(&format!("  - [{}]({})\n", escape(file.name()), file.url()));
+            }
+        }
+    }
+
+    text
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('[', "\\[").replace(']', "\\]").replace('*', "\\*").replace('_', "\\_")
+}
+
+#[test]
+fn test_export_format() {
+    assert_eq!(ExportFormat::parse("md"), Some(ExportFormat::Markdown));
+    assert_eq!(ExportFormat::parse("json").map(|f| f.extension()), Some("json"));
+    assert_eq!(escape("Sheet [1]_a"), "Sheet \\[1\\]\\_a");
+}
+
+#[test]
+fn test_export_course() {
+    let page = r#"<html><body><h1>Analysis 1</h1><div id="page-content"><ul>
+        <li class="section main" aria-label="General"><ul>
+            <li class="activity forum modtype_forum" id="module-1"><div class="activityinstance"><a class="aalink" href="https://www.moodle.tum.de/mod/forum/view.php?id=1"><span class="instancename">News<span class="accesshide "> Forum</span></span></a></div></li>
+        </ul></li>
+        <li class="section main"><h3 class="sectionname">Week 1</h3><ul>
+            <li class="activity assign modtype_assign" id="module-2"><div class="activityinstance"><a class="aalink" href="https://www.moodle.tum.de/mod/assign/view.php?id=2"><span class="instancename">Sheet_1<span class="accesshide "> Aufgabe</span></span></a></div><div data-region="activity-dates"><div>Due: 1 May</div></div></li>
+            <li class="activity resource modtype_resource" id="module-3"><div class="activityinstance"><div class="dimmed_text"><span class="instancename">Solution 1<span class="accesshide "> Datei</span></span></div></div><div class="availabilityinfo isrestricted">Not available unless: It is on or after 8 May</div></li>
+        </ul></li>
+    </ul></div></body></html>"#;
+    let course = MoodleCourseData::from_html("https://www.moodle.tum.de/course/view.php?id=7", page.to_string());
+
+    assert_eq!(export(&course, ExportFormat::Markdown), "# Analysis 1\n\n<https://www.moodle.tum.de/course/view.php?id=7>\n\n\
+        ## General\n\n- [News](https://www.moodle.tum.de/mod/forum/view.php?id=1) *(Forum)*\n\n\
+        ## Week 1\n\n- [Sheet\\_1](https://www.moodle.tum.de/mod/assign/view.php?id=2) *(Aufgabe)*\n  - Due: 1 May\n\
+        - Solution 1 *(Datei)*\n  - Not available unless: It is on or after 8 May\n");
+
+    let json = serde_json::from_str::<serde_json::Value>(&export(&course, ExportFormat::Json)).unwrap();
+    assert_eq!(json["name"], "Analysis 1");
+    assert_eq!(json["url"], "https://www.moodle.tum.de/course/view.php?id=7");
+    let sections = json["sections"].as_array().unwrap();
+    assert_eq!(sections.iter().map(|s| s["name"].as_str().unwrap()).collect::<Vec<_>>(), vec!["General", "Week 1"]);
+    assert_eq!(sections[1]["activities"][0]["dates"], serde_json::json!(["Due: 1 May"]));
+    assert_eq!(sections[1]["activities"][1]["restriction"], "Not available unless: It is on or after 8 May");
+    assert_eq!(sections[1]["activities"][1]["url"], serde_json::Value::Null);
+}
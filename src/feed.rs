@@ -0,0 +1,153 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+
+use crate::moodle::*;
+use crate::notify::ChangeEvent;
+
+pub const ATOM_CONTENT_TYPE: &str = "application/atom+xml; charset=utf-8";
+
+const ENTRIES_PER_COURSE: usize = 50;
+const ENTRIES_COMBINED: usize = 100;
+
+// Recent changes of every watched course, kept in memory for the Atom feeds
+pub struct History {
+    courses: HashMap<u32, CourseHistory>
+}
+
+struct CourseHistory {
+    name: String,
+    url: String,
+    entries: VecDeque<HistoryEntry>
+}
+
+#[derive(Clone)]
+struct HistoryEntry {
+    course: u32,
+    time: DateTime<Utc>,
+    changes: Vec<MoodleChange>
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            courses: HashMap::new()
+        }
+    }
+
+    pub fn watch(&mut self, course: &MoodleCourseData) {
+        self.course(course.id(), course.name(), course.url());
+    }
+
+    pub fn record(&mut self, event: &ChangeEvent) {
+        let entries = &mut self.course(event.course_id, &event.course_name, &event.course_url).entries;
+        entries.push_front(HistoryEntry {
+            course: event.course_id,
+            time: event.time,
+            changes: event.changes.clone()
+        });
+        entries.truncate(ENTRIES_PER_COURSE);
+    }
+
+    fn course(&mut self, id: u32, name: &str, url: &str) -> &mut CourseHistory {
+        let history = self.courses.entry(id).or_insert_with(|| CourseHistory {
+            name: String::new(),
+            url: String::new(),
+            entries: VecDeque::new()
+        });
+        history.name = name.to_string();
+        history.url = url.to_string();
+        history
+    }
+
+    pub fn course_feed(&self, id: u32) -> Option<String> {
+        let history = self.courses.get(&id)?;
+        let entries = history.entries.iter().cloned().collect::<Vec<_>>();
+        Some(self.atom(&format!("Poodle: {}", history.name), &format!("urn:poodle:course:{}", id), Some(&history.url), &entries))
+    }
+
+    pub fn combined_feed(&self) -> String {
+        let mut entries = self.courses.values().flat_map(|h| h.entries.iter().cloned()).collect::<Vec<_>>();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.time));
+        entries.truncate(ENTRIES_COMBINED);
+
+        self.atom("Poodle: all courses", "urn:poodle:courses", None, &entries)
+    }
+
+    fn atom(&self, title: &str, id: &str, link: Option<&str>, entries: &[HistoryEntry]) -> String {
+        let updated = entries.first().map(|e| e.time).unwrap_or_else(Utc::now);
+
+        let mut feed = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        feed.push_str(&format!("  <title>{}</title>\n  <id>{}</id>\n  <updated>{}</updated>\n", escape(title), id, updated.to_rfc3339()));
+        // Atom requires an author for every entry, which entries inherit from the feed
+        feed.push_str("  <author><name>Poodle</name></author>\n");
+        if let Some(link) = link {
+            feed.push_str(&format!("  <link href=\"{}\"/>\n", escape(link)));
+        }
+
+        for entry in entries {
+            let course = &self.courses[&entry.course];
+            let content = entry.changes.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("\n");
+
+            feed.push_str("  <entry>\n");
+            feed.push_str(&format!("    <title>Update in course {}</title>\n", escape(&course.name)));
+            feed.push_str(&format!("    <id>urn:poodle:course:{}:{}</id>\n", entry.course, entry.time.timestamp_millis()));
+            feed.push_str(&format!("    <updated>{}</updated>\n", entry.time.to_rfc3339()));
+            feed.push_str(&format!("    <link href=\"{}\"/>\n", escape(&course.url)));
+            feed.push_str(&format!("    <content type=\"text\">{}</content>\n", escape(&content)));
+            feed.push_str("  </entry>\n");
+        }
+
+        feed.push_str("</feed>\n");
+        feed
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[test]
+fn test_feed_escape() {
+    assert_eq!(escape("Q&A <\"1\">"), "Q&amp;A &lt;&quot;1&quot;&gt;");
+}
+
+// The update times of the entries in the order they appear in the feed
+#[cfg(test)]
+fn entry_times(feed: &str) -> Vec<DateTime<Utc>> {
+    feed.split("<entry>").skip(1).map(|entry| {
+        let updated = entry.split("<updated>").nth(1).and_then(|u| u.split("</updated>").next()).unwrap();
+        DateTime::parse_from_rfc3339(updated).unwrap().with_timezone(&Utc)
+    }).collect()
+}
+
+#[test]
+fn test_feed_history() {
+    let mut history = History::new();
+    let start = Utc::now();
+    for i in 0..60 {
+        for course in 1..=3 {
+            let mut event = ChangeEvent::example();
+            event.course_id = course;
+            event.course_name = format!("Course {}", course);
+            event.time = start + chrono::Duration::seconds(i * 3 + course as i64);
+            history.record(&event);
+        }
+    }
+
+    let feed = history.course_feed(2).unwrap();
+    let times = entry_times(&feed);
+    assert!(feed.contains("<title>Poodle: Course 2</title>"));
+    assert!(feed.contains("<author><name>Poodle</name></author>"));
+    assert!(!feed.contains("Course 1"));
+    assert_eq!(times.len(), ENTRIES_PER_COURSE);
+    assert_eq!(times[0], start + chrono::Duration::seconds(59 * 3 + 2));
+    assert!(times.windows(2).all(|t| t[0] > t[1]));
+
+    let times = entry_times(&history.combined_feed());
+    assert_eq!(times.len(), ENTRIES_COMBINED);
+    assert_eq!(times[0], start + chrono::Duration::seconds(59 * 3 + 3));
+    assert!(times.windows(2).all(|t| t[0] > t[1]));
+
+    assert!(history.course_feed(4).is_none());
+}
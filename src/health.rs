@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use tokio::time::{Duration, Instant};
+
+// What the liveness and readiness probes are based on, updated by the gateway events and the poll loop
+pub struct Health {
+    started: Instant,
+    max_age: Duration,
+    discord_connected: bool,
+    logged_in: HashMap<String, bool>,
+    last_poll: Option<Instant>,
+    last_success: Option<Instant>
+}
+
+impl Health {
+    // Polls older than max_age count as stuck
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            started: Instant::now(),
+            max_age,
+            discord_connected: false,
+            logged_in: HashMap::new(),
+            last_poll: None,
+            last_success: None
+        }
+    }
+
+    pub fn set_discord(&mut self, connected: bool) {
+        self.discord_connected = connected;
+    }
+
+    pub fn set_logged_in(&mut self, account: &str, logged_in: bool) {
+        self.logged_in.insert(account.to_string(), logged_in);
+    }
+
+    // Called after every poll, whether its courses could be updated or not
+    pub fn poll_completed(&mut self, succeeded: bool) {
+        let now = Instant::now();
+        self.last_poll = Some(now);
+        if succeeded {
+            self.last_success = Some(now);
+        }
+    }
+
+    // Alive as long as polls keep completing, the startup counts as a poll so the first one has time to finish
+    pub fn live(&self, now: Instant) -> bool {
+        now.duration_since(self.last_poll.unwrap_or(self.started)) <= self.max_age
+    }
+
+    // Failing courses and logins only make the bot unready, restarting it wouldn't fix them
+    pub fn ready(&self, now: Instant) -> bool {
+        self.live(now) && now.duration_since(self.last_success.unwrap_or(self.started)) <= self.max_age
+            && self.discord_connected && self.logged_in.values().all(|l| *l)
+    }
+
+    pub fn report(&self, now: Instant) -> String {
+        let mut text = String::new();
+        writeln!(text, "discord: {}", if self.discord_connected { "connected" } else { "disconnected" }).unwrap();
+
+        let mut accounts = self.logged_in.iter().collect::<Vec<_>>();
+        accounts.sort();
+        for (account, logged_in) in accounts {
+            writeln!(text, "moodle {}: {}", account, if *logged_in { "logged in" } else { "login failing" }).unwrap();
+        }
+
+        match self.last_success {
+            Some(time) => writeln!(text, "last successful poll: {}s ago", now.duration_since(time).as_secs()).unwrap(),
+            None => writeln!(text, "last successful poll: never").unwrap()
+        }
+        text
+    }
+}
+
+#[test]
+fn test_health() {
+    let mut health = Health::new(Duration::from_secs(900));
+    let now = Instant::now();
+    assert!(health.live(now));
+    assert!(!health.ready(now));
+
+    health.set_discord(true);
+    health.set_logged_in("default", true);
+    assert!(health.ready(now));
+
+    health.set_logged_in("default", false);
+    assert!(!health.ready(now));
+    assert!(!health.live(now + Duration::from_secs(901)));
+
+    // A course that keeps failing doesn't get the bot restarted
+    let mut health = Health::new(Duration::from_millis(50));
+    health.set_discord(true);
+    std::thread::sleep(std::time::Duration::from_millis(60));
+    health.poll_completed(false);
+    assert!(health.live(Instant::now()));
+    assert!(!health.ready(Instant::now()));
+
+    health.poll_completed(true);
+    assert!(health.ready(Instant::now()));
+}
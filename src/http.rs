@@ -0,0 +1,120 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use tracing::{error, info, warn};
+
+// Just enough HTTP/1.1 to answer GET requests from feed readers and monitoring, one request per connection
+pub struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: String) -> Self {
+        Self {
+            status: 200,
+            content_type,
+            body
+        }
+    }
+
+    pub fn unavailable(body: String) -> Self {
+        Self {
+            status: 503,
+            content_type: "text/plain; charset=utf-8",
+            body
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self {
+            status: 404,
+            content_type: "text/plain; charset=utf-8",
+            body: "Not found\n".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+impl Response {
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+}
+
+pub async fn serve<F, Fut>(addr: String, handler: F)
+    where F: Fn(String) -> Fut + Send + Sync + 'static, Fut: Future<Output = Response> + Send {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Listening for HTTP requests on {}", addr);
+
+    let handler = Arc::new(handler);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, handler.as_ref()).await {
+                        warn!("Error handling HTTP request: {}", e);
+                    }
+                });
+            },
+            Err(e) => warn!("Error accepting HTTP connection: {}", e)
+        }
+    }
+}
+
+async fn handle<F, Fut>(mut stream: TcpStream, handler: &F) -> std::io::Result<()>
+    where F: Fn(String) -> Fut, Fut: Future<Output = Response> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < 8192 {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    let mut parts = request.lines().next().unwrap_or("").split(' ');
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("/").split('?').next().unwrap_or("/").to_string();
+
+    let response = match method {
+        "GET" | "HEAD" => handler(path).await,
+        _ => Response {
+            status: 405,
+            content_type: "text/plain; charset=utf-8",
+            body: "Method not allowed\n".to_string()
+        }
+    };
+
+    let reason = match response.status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => ""
+    };
+    let mut out = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", response.status, reason, response.content_type, response.body.len());
+    if method != "HEAD" {
+        out.push_str(&response.body);
+    }
+
+    stream.write_all(out.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,28 @@
+use tracing_subscriber::EnvFilter;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json
+}
+
+// RUST_LOG overrides the configured level, which only applies to poodle itself while other crates log warnings
+pub fn init(level: &str, format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(directives(level)));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init()
+    }
+}
+
+// EnvFilter::new() drops directives it can't parse, so the level is checked with the config
+pub fn valid_level(level: &str) -> bool {
+    EnvFilter::try_new(directives(level)).is_ok()
+}
+
+fn directives(level: &str) -> String {
+    format!("warn,poodle={}", level)
+}
+
@@ -1,251 +1,848 @@
 use std::sync::Arc;
-use std::collections::HashMap;
-use std::fs::read_to_string;
+use std::collections::{HashMap, HashSet};
+use std::process::exit;
 
 use serenity::prelude::*;
 use serenity::async_trait;
+use serenity::client::bridge::gateway::event::ShardStageUpdateEvent;
+use serenity::gateway::ConnectionStage;
+use serenity::model::event::ResumedEvent;
 use serenity::model::gateway::Ready;
 use serenity::model::channel::*;
 use serenity::model::id::*;
+use serenity::model::interactions::Interaction;
+use serenity::http::Http;
 use serenity::utils::Colour;
 
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
 
 use rand::{thread_rng, Rng};
 
-use config::*;
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
 
 mod moodle;
 use moodle::*;
 
+mod scheduler;
+use scheduler::*;
+
+mod alert;
+use alert::*;
+
+mod conf;
+use conf::*;
+
+mod mirror;
+use mirror::*;
+
+mod export;
+use export::*;
+
+mod feed;
+use feed::*;
+
+mod http;
+
+mod notify;
+use notify::*;
+
+mod webhook;
+use webhook::*;
+
+mod email;
+use email::*;
+
+mod metrics;
+use metrics::*;
+
+mod health;
+use health::*;
+
+mod logging;
+
+mod slash;
+use slash::SlashCommand;
+
+mod permissions;
+
+const POLL_INTERVAL: u64 = 300;
+
+mod cli;
+
 #[tokio::main]
 async fn main() {
-    let mut conf = Config::default();
-    match read_to_string("./poodle.toml") {
-        Ok(c) => { conf.merge(File::from_str(&c, FileFormat::Toml)).unwrap(); },
-        Err(_) => { conf.merge(File::from_str(&read_to_string("/etc/poodle/poodle.toml").expect("Config file not found"), FileFormat::Toml)).unwrap(); }
-    }
-
-    let auth = MoodleAuthConf::ShibbolethUser(conf.get_str("user").expect("Key \"user\" missing from config"), conf.get_str("pass").expect("Key \"user\" missing from config"));
-    let conf = Conf {
-        discord_token: conf.get_str("token").expect("Key \"token\" missing from config"),
-        discord_client_id: conf.get_str("client").expect("Key \"client\" missing from config"),
-        discord_channel_id: (conf.get_int("channel").expect("Key \"channel\" missing from config") as u64).into(),
-        course_ids: conf.get_array("courses").expect("Key \"courses\" missing from config").iter().map(|v| v.clone().into_str().expect("Expected string courses in config")).collect(),
-        responses: conf.get_array("responses").expect("Key \"responses\" missing from config").iter().map(|v| v.clone().into_str().expect("Expected string responses in config")).collect()
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let config_path = match args.iter().position(|a| a == "--config") {
+        Some(i) if i + 1 < args.len() => {
+            args.remove(i);
+            Some(args.remove(i))
+        },
+        Some(_) => {
+            eprintln!("Expected a path after --config");
+            exit(2);
+        },
+        None => std::env::var("POODLE_CONFIG").ok()
     };
 
-    let mut client = Client::builder(conf.discord_token.clone()).event_handler(Handler::new(conf, auth)).await.expect("Failed to construct Discord client");
-    client.start().await.expect("Error running Discord client");
+    if args.first().map(|a| a.as_str()) == Some("diff") {
+        cli::diff(&args);
+        return;
+    }
+
+    let loaded = if args.is_empty() { Conf::load(config_path.as_deref()) } else { Conf::load_cli(config_path.as_deref()) };
+    let (conf, accounts) = match loaded {
+        Ok(res) => res,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    logging::init(&conf.log_level, conf.log_format);
+
+    if !args.is_empty() {
+        cli::run(&args, conf, accounts).await;
+        return;
+    }
+
+    let scheduler = Arc::new(Scheduler::new());
+    let history = Arc::new(Mutex::new(History::new()));
+    let metrics = Metrics::new();
+    let health = Arc::new(std::sync::Mutex::new(Health::new(Duration::from_secs(3 * POLL_INTERVAL))));
+
+    let mut notifiers = Notifiers::new();
+    notifiers.add(FeedNotifier::new(history.clone()));
+    for hook in &conf.webhooks {
+        notifiers.add(WebhookNotifier::new(hook.clone()));
+    }
+    // The transport was already built once while checking the config
+    if let Some(notifier) = conf.email.clone().and_then(|email| EmailNotifier::new(email).ok()) {
+        notifier.start_digest();
+        notifiers.add(notifier);
+    }
+
+    if let Some(addr) = conf.http_listen.clone() {
+        let history = history.clone();
+        let metrics = metrics.clone();
+        let health = health.clone();
+        tokio::spawn(http::serve(addr, move |path| {
+            let history = history.clone();
+            let metrics = metrics.clone();
+            let health = health.clone();
+            async move { route(&history, &metrics, &health, &path).await }
+        }));
+    }
+
+    let token = conf.discord_token.clone();
+    let handler = Handler::new(conf, accounts, scheduler.clone(), history, notifiers, metrics, health);
+
+    // Listening right away keeps a reload during startup from terminating the bot
+    match signal(SignalKind::hangup()) {
+        Ok(hangup) => {
+            let handler = handler.clone();
+            tokio::spawn(async move { handler.reload_on_hangup(hangup).await });
+        },
+        Err(e) => error!("Failed to listen for SIGHUP, config reloading is disabled: {}", e)
+    }
+
+    let mut client = Client::builder(token).event_handler(handler).await.expect("Failed to construct Discord client");
+    let res = client.start().await;
+    scheduler.cancel();
+    res.expect("Error running Discord client");
 }
 
+#[derive(Clone)]
 struct Handler {
-    context: Arc<Mutex<MoodleContext>>,
-    subscribers: Arc<Mutex<HashMap<ChannelId, Vec<MoodleCourseData>>>>,
-    conf: Arc<Conf>,
-    groups: Arc<Mutex<Vec<String>>>
+    accounts: Arc<HashMap<String, MoodleAuthConf>>,
+    contexts: Arc<HashMap<String, Arc<Mutex<MoodleContext>>>>,
+    subscribers: Arc<Mutex<HashMap<ChannelId, Subscription>>>,
+    conf: Arc<SharedConf>,
+    groups: Arc<Mutex<Vec<String>>>,
+    scheduler: Arc<Scheduler>,
+    history: Arc<Mutex<History>>,
+    notifiers: Notifiers,
+    metrics: Metrics,
+    health: Arc<std::sync::Mutex<Health>>
+}
+
+struct Subscription {
+    account: String,
+    courses: Vec<MoodleCourseData>,
+    // Ids of the courses only watched because the config lists them, a reload leaves the others alone
+    configured: Vec<u32>
 }
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
-        println!("Connected as {}", ready.user.name);
+        info!("Connected as {}", ready.user.name);
+        self.health.lock().unwrap().set_discord(true);
 
-        let context = self.context.clone();
+        let contexts = self.contexts.clone();
         let subscribers = self.subscribers.clone();
-        let conf = self.conf.clone();
-
-        for word in &conf.course_ids {
-            if let Ok(id) = word.parse() {
-                if let Ok(course) = context.lock().await.get(id).await {
-                    let mut subscribers = subscribers.lock().await;
-                    if subscribers.contains_key(&conf.discord_channel_id) {
-                        if let None = subscribers.get(&conf.discord_channel_id).unwrap().iter().position(|e| e.id() == id) {
-                            subscribers.get_mut(&conf.discord_channel_id).unwrap().push(course);
+        let shared = self.conf.clone();
+        let conf = shared.get();
+        let failures = Arc::new(Mutex::new(FailureTracker::new(conf.alert_threshold)));
+        let mirror = Arc::new(conf.mirror_dir.as_ref().map(Mirror::new));
+        // Courses whose last sync got every file, the others are synced again with the next poll
+        let mirrored = Arc::new(Mutex::new(HashSet::new()));
+
+        let mut notifiers = self.notifiers.clone();
+        notifiers.add(DiscordNotifier::new(ctx.http.clone(), shared.clone(), self.metrics.clone()));
+        let notifiers = Arc::new(notifiers);
+        let metrics = self.metrics.clone();
+        let health = self.health.clone();
+        let http = ctx.http.clone();
+
+        let started = self.scheduler.start(Duration::from_secs(POLL_INTERVAL), move || {
+            let contexts = contexts.clone();
+            let subscribers = subscribers.clone();
+            let conf = shared.get();
+            let failures = failures.clone();
+            let mirror = mirror.clone();
+            let mirrored = mirrored.clone();
+            let notifiers = notifiers.clone();
+            let metrics = metrics.clone();
+            let health = health.clone();
+            let ctx = ctx.clone();
+
+            async move {
+                let mut failed = false;
+                let mut failed_logins = Vec::new();
+                let mut to_mirror = Vec::new();
+                // Alerts and notifications wait until the subscribers are unlocked, so slow webhooks or mail servers don't
+                // hold up the commands
+                let mut alerts = Vec::new();
+                let mut events = Vec::new();
+                let mut subscribers = subscribers.lock().await;
+
+                for (id, account) in polled_courses(&subscribers) {
+                    // Once the account's login failed, its other courses wait for the next poll, as every further try counts
+                    // against the account at the identity provider
+                    if failed_logins.contains(&account) {
+                        continue;
+                    }
+
+                    let context = contexts[&account].clone();
+                    let channels = subscribers.iter().filter(|(_, s)| s.courses.iter().any(|c| c.id() == id)).map(|(c, _)| *c).collect::<Vec<_>>();
+                    let mut course = match subscribers.values().flat_map(|s| s.courses.iter()).find(|c| c.id() == id) {
+                        Some(course) => course.clone(),
+                        None => continue
+                    };
+
+                    let res = context.lock().await.update(&mut course).await;
+
+                    match &res {
+                        Ok(_) => {
+                            health.lock().unwrap().set_logged_in(&account, true);
+                            let mut failures = failures.lock().await;
+                            for source in &[FailureSource::Login(account.clone()), FailureSource::Course(id)] {
+                                if failures.success(source.clone()) {
+                                    alerts.push(format!("Recovered: {} is working again", source));
+                                }
+                            }
+                        },
+                        Err(e) => {
+                            failed = true;
+                            let source = match e {
+                                MoodleErr::Login{ .. } => {
+                                    health.lock().unwrap().set_logged_in(&account, false);
+                                    failed_logins.push(account.clone());
+                                    FailureSource::Login(account.clone())
+                                },
+                                _ => FailureSource::Course(id)
+                            };
+                            let mut failures = failures.lock().await;
+                            if failures.failure(source.clone()) {
+                                alerts.push(format!("Alert: {} failed {} times in a row: {}", source, failures.threshold(), e));
+                            }
                         }
-                    } else {
-                        subscribers.insert(conf.discord_channel_id, vec![course]);
                     }
 
-                    println!("Channel {} is watching course {}", conf.discord_channel_id, id);
-                } else {
-                    eprintln!("Failed to fetch course data for {}", id)
+                    // A course is only synced again when it changed or files are still missing, as syncing asks Moodle about
+                    // every resource whose file isn't tracked
+                    if let (Ok(changes), Some(_)) = (&res, mirror.as_ref()) {
+                        if !changes.is_empty() || !mirrored.lock().await.contains(&id) {
+                            to_mirror.push((context.clone(), course.clone()));
+                        }
+                    }
+
+                    match res {
+                        Ok(changes) if !changes.is_empty() => {
+                            info!(course = id, "Update in course, {} changes", changes.len());
+                            metrics.changes(id, changes.len());
+                            events.push(ChangeEvent::new(channels, &course, changes));
+                        },
+                        Ok(_) => (),
+                        Err(e) => error!(course = id, "Failed to update course: {}", e)
+                    }
+
+                    // Every channel continues from the same state
+                    for c in subscribers.values_mut().flat_map(|s| s.courses.iter_mut()).filter(|c| c.id() == id) {
+                        *c = course.clone();
+                    }
                 }
-            }
-        }
 
-        tokio::spawn(async move { loop {
-            for (channel, cache) in subscribers.lock().await.iter_mut() {
-                for mut course in cache.iter_mut() {
-                    if let Ok(Some(diff)) = context.lock().await.update(&mut course).await {
-                        println!("Update in course {}", course.id());
-
-                        if let Err(e) = channel.send_message(&ctx.http, |m| {
-                            m.embed(|e| {
-                                e.title(format!("Update in course {}", course.name().clone()));
-                                e.url(course.url().clone());
-                                e.description(format!("{}\n{}", diff, get_resp(&conf)));
-                                e
-                            });
-                            m
-                        }).await {
-                            eprintln!("Error sending message: {}", e);
+                drop(subscribers);
+                health.lock().unwrap().poll_completed(!failed);
+
+                for alert in alerts {
+                    send_alert(&ctx.http, conf.admin_channel_id, conf.admin_user_id, &alert).await;
+                }
+                for event in events {
+                    notifiers.notify(&event).await;
+                }
+
+                if let Some(mirror) = mirror.as_ref() {
+                    for (context, course) in to_mirror {
+                        let complete = match mirror.sync(&context, &course).await {
+                            Ok(sync) if sync.failed > 0 => {
+                                warn!(course = course.id(), "Mirrored course, {} files downloaded, {} failed and are retried with the next poll", sync.downloaded, sync.failed);
+                                false
+                            },
+                            Ok(_) => true,
+                            Err(e) => {
+                                error!(course = course.id(), "Failed to mirror course: {}", e);
+                                false
+                            }
+                        };
+
+                        let mut mirrored = mirrored.lock().await;
+                        if complete {
+                            mirrored.insert(course.id());
+                        } else {
+                            mirrored.remove(&course.id());
                         }
                     }
                 }
             }
+        });
+
+        if !started {
+            info!("Reconnected, poll loop is already running");
+            return;
+        }
 
-            sleep(Duration::from_secs_f32(300.0)).await;
-        }});
+        let conf = self.conf.get();
+        for channel in &conf.channels {
+            self.watch_configured(channel, &channel.courses).await;
+        }
+
+        match conf.discord_client_id.parse() {
+            Ok(application_id) => slash::register(&http, application_id).await,
+            Err(_) => error!("Client id {} is not numeric, slash commands are not registered", conf.discord_client_id)
+        }
+    }
+
+    async fn resume(&self, _ctx: Context, _: ResumedEvent) {
+        self.health.lock().unwrap().set_discord(true);
+    }
+
+    async fn shard_stage_update(&self, _ctx: Context, event: ShardStageUpdateEvent) {
+        self.health.lock().unwrap().set_discord(event.new == ConnectionStage::Connected);
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let span = info_span!("command", name = field::Empty, channel = %interaction.channel_id, user = %interaction.member.user.id);
+        self.slash_command(ctx, interaction).instrument(span).await;
     }
 
     async fn message(&self, ctx: Context, msg: Message) {
-        let context = self.context.clone();
+        let span = info_span!("command", name = field::Empty, channel = %msg.channel_id, user = %msg.author.id);
+        self.command(ctx, msg).instrument(span).await;
+    }
+}
+
+impl Handler {
+    fn new(conf: Conf, accounts: HashMap<String, MoodleAuthConf>, scheduler: Arc<Scheduler>, history: Arc<Mutex<History>>, notifiers: Notifiers, metrics: Metrics, health: Arc<std::sync::Mutex<Health>>) -> Self {
+        Self {
+            accounts: Arc::new(accounts.clone()),
+            contexts: Arc::new(accounts.into_iter().map(|(name, auth)| {
+                let mut context = MoodleContext::new(auth, conf.file_tracking);
+                context.set_metrics(metrics.clone());
+                (name, Arc::new(Mutex::new(context)))
+            }).collect()),
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            conf: Arc::new(SharedConf::new(conf)),
+            groups: Arc::new(Mutex::new(Vec::new())),
+            scheduler,
+            history,
+            notifiers,
+            metrics,
+            health
+        }
+    } 
+
+    async fn command(&self, ctx: Context, msg: Message) {
         let subscribers = self.subscribers.clone();
-        let conf = self.conf.clone();
-        let groups = self.groups.clone();
+        let conf = self.conf.get();
+        let metrics = self.metrics.clone();
 
         let words = msg.content.split(" ").collect::<Vec<_>>();
         if (msg.content.starts_with(&format!("<@!{}>", conf.discord_client_id)) ||
             msg.content.starts_with(&format!("<@{}>", conf.discord_client_id))) &&
             words.len() >= 2 {
             let cmd = words[1];
+            Span::current().record("name", &cmd);
+            metrics.command(cmd);
+
+            // Rules for account and makegroups only cover changing the account or members, asking for them is always allowed
+            let guarded = !matches!(cmd, "account" | "makegroups") || words.len() >= 3;
+            let roles = msg.member.as_ref().map(|m| m.roles.as_slice()).unwrap_or_default();
+            if guarded && !conf.permissions.allows(cmd, msg.author.id, roles) {
+                info!("Denied command to user {}", msg.author.name);
+                if let Err(e) = msg.channel_id.say(&ctx.http, format!("{} (you are not allowed to use {})", get_resp(&conf, msg.channel_id), cmd)).await {
+                    metrics.discord_send_error();
+                    error!("Error sending message: {}", e);
+                }
+                return;
+            }
 
             if cmd == "watch" && words.len() >= 3 {
                 for word in words[2..].iter() {
                     if let Ok(id) = word.parse() {
-                        let mut subscribers = subscribers.lock().await;
-                        if let Ok(course) = context.lock().await.get(id).await {
-                            if subscribers.contains_key(&msg.channel_id) {
-                                if let None = subscribers.get(&msg.channel_id).unwrap().iter().position(|e| e.id() == id) {
-                                    subscribers.get_mut(&msg.channel_id).unwrap().push(course);
-                                }
-                            } else {
-                                subscribers.insert(msg.channel_id, vec![course]);
-                            }
-
-                            if let Err(e) = msg.channel_id.say(&ctx.http, format!("{} (watching course {})", get_resp(&conf), id)).await {
-                                eprintln!("Error sending message: {}", e);
-                            }
-                            println!("Channel {} is watching course {}", msg.channel_id, id);
-                        } else {
-                            eprintln!("Failed to fetch course data for {}", id)
+                        let text = self.watch_command(msg.channel_id, msg.guild_id, id).await;
+                        if let Err(e) = msg.channel_id.say(&ctx.http, text).await {
+                            metrics.discord_send_error();
+                            error!("Error sending message: {}", e);
                         }
                     }
                 }
             } else if cmd == "unwatch" && words.len() >= 3 {
                 for word in words[2..].iter() {
                     if let Ok(id) = word.parse::<u32>() {
-                        if let Some(cache) = self.subscribers.lock().await.get_mut(&msg.channel_id) {
-                            if let Some(course_index) = cache.iter().position(|e| e.id() == id) {
-                                cache.remove(course_index);
-
-                                if let Err(e) = msg.channel_id.say(&ctx.http, format!("{} (no longer watching course {})", get_resp(&conf), id)).await {
-                                    eprintln!("Error sending message: {}", e);
-                                }
-                                println!("Channel {} is no longer watching course {}", msg.channel_id, id);
+                        if let Some(text) = self.unwatch_command(msg.channel_id, id).await {
+                            if let Err(e) = msg.channel_id.say(&ctx.http, text).await {
+                                metrics.discord_send_error();
+                                error!("Error sending message: {}", e);
                             }
                         }
                     }
                 }
             } else if cmd == "timer" && words.len() == 3 {
                 if let Ok(time) = words[2].parse() {
-                    if let Err(e) = msg.channel_id.say(&ctx.http, format!("{} (timer set for {} seconds)", get_resp(&conf), time)).await {
-                        eprintln!("Error sending message: {}", e);
+                    if let Err(e) = msg.channel_id.say(&ctx.http, self.timer_command(ctx.http.clone(), msg.channel_id, time)).await {
+                        metrics.discord_send_error();
+                        error!("Error sending message: {}", e);
                     }
-
-                    tokio::spawn(async move {
-                        sleep(Duration::from_secs(time)).await;
-
-                        if let Err(e) = msg.channel_id.say(&ctx.http, format!("{} (timer done)", get_resp(&conf))).await {
-                            eprintln!("Error sending message: {}", e);
-                        }
-                    });
                 }
             } else if cmd == "makegroups" && words.len() == 2 {
-                let mut groups = groups.lock().await.clone();
-                let group_size = (groups.len() as f32 * 2.0).log2().floor() as usize;
-                let group_count = groups.len() as usize / group_size;
-
-                // Random permutation by Fisher-Yates Shuffle
-                let mut perm: Vec<Vec<String>> = Vec::with_capacity(group_count);
-                for _ in 0..group_count {
-                    perm.push(Vec::with_capacity(group_size));
-                }
-
-                for i in 0..groups.len() {
-                    let j = thread_rng().gen_range(0..groups.len());
-                    perm[i % group_count].push(groups.remove(j));
+                let texts = match self.make_groups().await {
+                    Some(groups) => vec![get_resp(&conf, msg.channel_id).to_string(), groups],
+                    None => vec![format!("{} (no members set)", get_resp(&conf, msg.channel_id))]
+                };
+
+                for text in texts {
+                    if let Err(e) = msg.channel_id.say(&ctx.http, text).await {
+                        metrics.discord_send_error();
+                        error!("Error sending message: {}", e);
+                    }
                 }
-
-                if let Err(e) = msg.channel_id.say(&ctx.http, get_resp(&conf)).await {
-                    eprintln!("Error sending message: {}", e);
+            } else if cmd == "makegroups" && words.len() >= 3 {
+                let members = words[2..].iter().map(|w| w.to_string()).collect();
+                if let Err(e) = msg.channel_id.say(&ctx.http, self.set_groups(msg.channel_id, members).await).await {
+                    metrics.discord_send_error();
+                    error!("Error sending message: {}", e);
                 }
-
-                let mut text = String::new();
-                for (i, group) in perm.iter().enumerate() {
-                    let mut substring = String::new();
-                    for e in group {
-                        substring.push_str(&format!("{} ", e));
-                    }
-                    text.push_str(&format!("Group {}: {}\n", i + 1, substring));
+            } else if cmd == "account" && words.len() == 2 {
+                let account = match subscribers.lock().await.get(&msg.channel_id) {
+                    Some(subscription) => subscription.account.clone(),
+                    None => conf.account_for(msg.channel_id, msg.guild_id)
+                };
+
+                if let Err(e) = msg.channel_id.say(&ctx.http, format!("{} (using Moodle account {})", get_resp(&conf, msg.channel_id), account)).await {
+                    metrics.discord_send_error();
+                    error!("Error sending message: {}", e);
                 }
+            } else if cmd == "account" && words.len() == 3 {
+                let account = words[2].to_string();
+                let text = if self.contexts.contains_key(&account) {
+                    subscribers.lock().await.entry(msg.channel_id).or_insert_with(|| Subscription {
+                        account: account.clone(),
+                        courses: Vec::new(),
+                        configured: Vec::new()
+                    }).account = account.clone();
+
+                    info!(account = %account, "Using Moodle account");
+                    format!("{} (now using Moodle account {})", get_resp(&conf, msg.channel_id), account)
+                } else {
+                    format!("{} (unknown Moodle account {})", get_resp(&conf, msg.channel_id), account)
+                };
 
                 if let Err(e) = msg.channel_id.say(&ctx.http, text).await {
-                    eprintln!("Error sending message: {}", e);
+                    metrics.discord_send_error();
+                    error!("Error sending message: {}", e);
                 }
-            } else if cmd == "makegroups" && words.len() >= 3 {
-                let mut groups = groups.lock().await;
-
-                groups.clear();
-                for word in words[2..].iter() {
-                    groups.push(word.to_string());
+            } else if cmd == "export" && (words.len() == 3 || words.len() == 4) {
+                if let (Ok(id), Some(format)) = (words[2].parse::<u32>(), ExportFormat::parse(words.get(3).unwrap_or(&"md"))) {
+                    let watched = subscribers.lock().await.get(&msg.channel_id).and_then(|s| s.courses.iter().find(|c| c.id() == id).cloned());
+                    let course = match watched {
+                        Some(course) => Ok(course),
+                        None => self.contexts[&conf.account_for(msg.channel_id, msg.guild_id)].lock().await.get(id).await
+                    };
+
+                    match course {
+                        Ok(course) => {
+                            let text = export(&course, format);
+                            let file_name = format!("course-{}.{}", id, format.extension());
+                            if let Err(e) = msg.channel_id.send_files(&ctx.http, vec![(text.as_bytes(), file_name.as_str())], |m| m.content(format!("{} (exported course {})", get_resp(&conf, msg.channel_id), id))).await {
+                                metrics.discord_send_error();
+                                error!("Error sending message: {}", e);
+                            }
+                        },
+                        Err(e) => {
+                            error!(course = id, "Failed to fetch course data: {}", e);
+                            if let Err(e) = msg.channel_id.say(&ctx.http, format!("{} (failed to export course {}: {})", get_resp(&conf, msg.channel_id), id, e)).await {
+                                metrics.discord_send_error();
+                                error!("Error sending message: {}", e);
+                            }
+                        }
+                    }
                 }
-
-                if let Err(e) = msg.channel_id.say(&ctx.http, format!("{} (created group with {} members)", get_resp(&conf), groups.len())).await {
-                    eprintln!("Error sending message: {}", e);
+            } else if cmd == "status" && words.len() == 2 {
+                if let Err(e) = msg.channel_id.say(&ctx.http, format!("{} ({})", get_resp(&conf, msg.channel_id), self.scheduler.report())).await {
+                    metrics.discord_send_error();
+                    error!("Error sending message: {}", e);
                 }
             } else if cmd == "send" && words.len() >= 3 {
-                let text = words[2..].join(" ");
-                for (channel, _) in subscribers.lock().await.iter_mut() {
-                    println!("User {} ({}) sent message \"{}\" to channel {}", msg.author.name, msg.author, text, channel);
-                    if let Err(e) = channel.send_message(&ctx.http, |m| {
-                        m.embed(|e| {
-                            e.title("PSA");
-                            e.colour(Colour::GOLD);
-                            e.description(format!("{}\n\n{}", text, get_resp(&conf)));
-                            e
-                        });
-                        m
-                    }).await {
-                        eprintln!("Error sending message: {}", e);
+                self.send(&ctx.http, &msg.author.name, &words[2..].join(" ")).await;
+            }
+        }
+    }
+
+    async fn slash_command(&self, ctx: Context, interaction: Interaction) {
+        let conf = self.conf.get();
+        let data = match interaction.data.as_ref() {
+            Some(data) => data,
+            None => return
+        };
+        Span::current().record("name", &data.name.as_str());
+        self.metrics.command(&data.name);
+
+        let application_id = match conf.discord_client_id.parse() {
+            Ok(id) => id,
+            Err(_) => return
+        };
+
+        if let Err(e) = slash::defer(&ctx.http, &interaction).await {
+            self.metrics.discord_send_error();
+            error!("Error responding to slash command: {}", e);
+            return;
+        }
+
+        let channel = interaction.channel_id;
+        let text = match SlashCommand::parse(data) {
+            None => {
+                error!("Unknown or malformed slash command");
+                format!("{} (invalid options for /{})", get_resp(&conf, channel), data.name)
+            },
+            Some(command) if command != SlashCommand::MakeGroups(None)
+                && !conf.permissions.allows(&data.name, interaction.member.user.id, &interaction.member.roles) => {
+                info!("Denied command to user {}", interaction.member.user.name);
+                format!("{} (you are not allowed to use {})", get_resp(&conf, channel), data.name)
+            },
+            Some(command) => self.run_slash_command(&ctx, &interaction, command).await
+        };
+
+        if let Err(e) = slash::reply(&ctx.http, application_id, &interaction, &text).await {
+            self.metrics.discord_send_error();
+            error!("Error responding to slash command: {}", e);
+        }
+    }
+
+    async fn run_slash_command(&self, ctx: &Context, interaction: &Interaction, command: SlashCommand) -> String {
+        let conf = self.conf.get();
+        let channel = interaction.channel_id;
+        match command {
+            SlashCommand::Watch(id) => self.watch_command(channel, Some(interaction.guild_id), id).await,
+            SlashCommand::Unwatch(id) => self.unwatch_command(channel, id).await
+                .unwrap_or_else(|| format!("{} (course {} is not watched in this channel)", get_resp(&conf, channel), id)),
+            SlashCommand::Timer(time) => self.timer_command(ctx.http.clone(), channel, time),
+            SlashCommand::MakeGroups(Some(members)) => self.set_groups(channel, members).await,
+            SlashCommand::MakeGroups(None) => match self.make_groups().await {
+                Some(groups) => format!("{}\n{}", get_resp(&conf, channel), groups),
+                None => format!("{} (no members set)", get_resp(&conf, channel))
+            },
+            SlashCommand::Send(text) => {
+                let count = self.send(&ctx.http, &interaction.member.user.name, &text).await;
+                format!("{} (sent to {} channels)", get_resp(&conf, channel), count)
+            }
+        }
+    }
+
+    // The commands shared by mentions and slash commands return the reply to post
+
+    async fn watch_command(&self, channel: ChannelId, guild: Option<GuildId>, id: u32) -> String {
+        let conf = self.conf.get();
+        match self.watch(channel, guild, id, false).await {
+            Ok(()) => {
+                info!(course = id, "Watching course");
+                format!("{} (watching course {})", get_resp(&conf, channel), id)
+            },
+            Err(e) => {
+                error!(course = id, "Failed to fetch course data: {}", e);
+                format!("{} (failed to watch course {}: {})", get_resp(&conf, channel), id, e)
+            }
+        }
+    }
+
+    // None if the course isn't watched in the channel
+    async fn unwatch_command(&self, channel: ChannelId, id: u32) -> Option<String> {
+        let mut subscribers = self.subscribers.lock().await;
+        let subscription = subscribers.get_mut(&channel)?;
+        let course_index = subscription.courses.iter().position(|e| e.id() == id)?;
+        subscription.courses.remove(course_index);
+        subscription.configured.retain(|c| *c != id);
+
+        info!(course = id, "No longer watching course");
+        Some(format!("{} (no longer watching course {})", get_resp(&self.conf.get(), channel), id))
+    }
+
+    fn timer_command(&self, http: Arc<Http>, channel: ChannelId, time: u64) -> String {
+        let conf = self.conf.get();
+        let metrics = self.metrics.clone();
+        let text = format!("{} (timer set for {} seconds)", get_resp(&conf, channel), time);
+
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(time)).await;
+
+            if let Err(e) = channel.say(&http, format!("{} (timer done)", get_resp(&conf, channel))).await {
+                metrics.discord_send_error();
+                error!("Error sending message: {}", e);
+            }
+        });
+
+        text
+    }
+
+    async fn set_groups(&self, channel: ChannelId, members: Vec<String>) -> String {
+        let mut groups = self.groups.lock().await;
+        *groups = members;
+        format!("{} (created group with {} members)", get_resp(&self.conf.get(), channel), groups.len())
+    }
+
+    // None if no members are set
+    async fn make_groups(&self) -> Option<String> {
+        let mut groups = self.groups.lock().await.clone();
+        if groups.is_empty() {
+            return None;
+        }
+        let group_size = (groups.len() as f32 * 2.0).log2().floor() as usize;
+        let group_count = groups.len() as usize / group_size;
+
+        // Random permutation by Fisher-Yates Shuffle
+        let mut perm: Vec<Vec<String>> = Vec::with_capacity(group_count);
+        for _ in 0..group_count {
+            perm.push(Vec::with_capacity(group_size));
+        }
+
+        for i in 0..groups.len() {
+            let j = thread_rng().gen_range(0..groups.len());
+            perm[i % group_count].push(groups.remove(j));
+        }
+
+        let mut text = String::new();
+        for (i, group) in perm.iter().enumerate() {
+            let mut substring = String::new();
+            for e in group {
+                substring.push_str(&format!("{} ", e));
+            }
+            text.push_str(&format!("Group {}: {}\n", i + 1, substring));
+        }
+        Some(text)
+    }
+
+    // Posts the text as a PSA in every channel that watches courses and returns the number of channels
+    async fn send(&self, http: &Http, author: &str, text: &str) -> usize {
+        let conf = self.conf.get();
+        let subscribers = self.subscribers.lock().await;
+        for channel in subscribers.keys() {
+            info!(target_channel = %channel, "User {} sent message \"{}\"", author, text);
+            if let Err(e) = channel.send_message(http, |m| {
+                m.embed(|e| {
+                    e.title("PSA");
+                    e.colour(Colour::GOLD);
+                    e.description(format!("{}\n\n{}", text, get_resp(&conf, *channel)));
+                    e
+                });
+                m
+            }).await {
+                self.metrics.discord_send_error();
+                error!("Error sending message: {}", e);
+            }
+        }
+        subscribers.len()
+    }
+
+    async fn watch_configured(&self, channel: &ChannelConf, ids: &[u32]) {
+        for &id in ids {
+            match self.watch(channel.channel, None, id, true).await {
+                Ok(()) => info!(channel = %channel.channel, name = %channel.name, course = id, "Watching course"),
+                Err(e) => error!(name = %channel.name, course = id, "Failed to fetch course data: {}", e)
+            }
+        }
+    }
+
+    // Notifiers, the HTTP server, logging, sessions and the poll loop keep the settings they were started with, changes to those
+    // are warned about. A config that adds or removes an account is rejected as channels and guilds could otherwise point at an
+    // account without a session.
+    async fn reload_on_hangup(&self, mut hangup: Signal) {
+        while hangup.recv().await.is_some() {
+            let old = self.conf.get();
+            match Conf::load(Some(&old.path)) {
+                Ok((_, accounts)) if accounts.len() != self.accounts.len() || accounts.keys().any(|a| !self.accounts.contains_key(a)) => {
+                    error!("Failed to reload config, keeping the previous one: Moodle accounts can only be added or removed with a restart");
+                },
+                Ok((conf, accounts)) => {
+                    let mut restart = old.restart_changes(&conf);
+                    let mut changed_accounts = accounts.iter().filter(|(name, auth)| self.accounts[*name] != **auth).map(|(name, _)| name.clone()).collect::<Vec<_>>();
+                    changed_accounts.sort();
+                    restart.extend(changed_accounts.into_iter().map(|name| format!("the credentials of Moodle account {}", name)));
+                    for key in restart {
+                        warn!("Changing {} only takes effect after a restart", key);
                     }
-                }
+
+                    let conf = self.conf.set(conf);
+                    self.apply_reload(&old, &conf).await;
+                    info!("Reloaded config from {}", conf.path);
+                },
+                Err(e) => error!("Failed to reload config, keeping the previous one: {}", e)
             }
         }
     }
+
+    // Applies the changed course lists of the configured channels, courses watched by command are left alone
+    async fn apply_reload(&self, old: &Conf, new: &Conf) {
+        for channel in &old.channels {
+            let kept = new.channel(channel.channel).map(|c| c.courses.as_slice()).unwrap_or_default();
+            let mut removed = channel.courses.iter().filter(|id| !kept.contains(id)).copied().collect::<Vec<_>>();
+
+            // Courses also watched with a command stay, as does the subscription with the account picked for the channel
+            if let Some(subscription) = self.subscribers.lock().await.get_mut(&channel.channel) {
+                removed.retain(|id| subscription.configured.contains(id));
+                subscription.courses.retain(|c| !removed.contains(&c.id()));
+                subscription.configured.retain(|id| !removed.contains(id));
+            }
+            for id in &removed {
+                info!(channel = %channel.channel, course = id, "No longer watching course");
+            }
+        }
+
+        for channel in &new.channels {
+            let known = old.channel(channel.channel).map(|c| c.courses.as_slice()).unwrap_or_default();
+            let added = channel.courses.iter().filter(|id| !known.contains(id)).copied().collect::<Vec<_>>();
+            self.watch_configured(channel, &added).await;
+        }
+    }
+
+    // A course watched with a command is no longer one of the configured ones, even if the config lists it as well
+    async fn watch(&self, channel: ChannelId, guild: Option<GuildId>, id: u32, configured: bool) -> Result<(), MoodleErr> {
+        let mut subscribers = self.subscribers.lock().await;
+        // A course other channels already watch joins their state, so every channel hears about the same changes
+        let known = subscribers.values().flat_map(|s| s.courses.iter()).find(|c| c.id() == id).cloned();
+        let subscription = subscribers.entry(channel).or_insert_with(|| Subscription {
+            account: self.conf.get().account_for(channel, guild),
+            courses: Vec::new(),
+            configured: Vec::new()
+        });
+
+        if subscription.courses.iter().all(|e| e.id() != id) {
+            let course = match known {
+                Some(course) => course,
+                None => self.contexts[&subscription.account].lock().await.get(id).await?
+            };
+            self.history.lock().await.watch(&course);
+            subscription.courses.push(course);
+            if configured {
+                subscription.configured.push(id);
+            }
+        } else if !configured {
+            subscription.configured.retain(|c| *c != id);
+        }
+
+        Ok(())
+    }
 }
 
-impl Handler {
-    fn new(conf: Conf, auth: MoodleAuthConf) -> Self {
-        Self {
-            context: Arc::new(Mutex::new(MoodleContext::new(auth))),
-            subscribers: Arc::new(Mutex::new(HashMap::new())),
-            conf: Arc::new(conf),
-            groups: Arc::new(Mutex::new(Vec::new()))
+// Serves /healthz, /readyz, /metrics, /feed.atom with the changes of all watched courses and /courses/<id>.atom per course
+async fn route(history: &Mutex<History>, metrics: &Metrics, health: &std::sync::Mutex<Health>, path: &str) -> http::Response {
+    if path == "/healthz" || path == "/readyz" {
+        let health = health.lock().unwrap();
+        let now = tokio::time::Instant::now();
+        let report = health.report(now);
+        let ok = if path == "/healthz" { health.live(now) } else { health.ready(now) };
+        return if ok { http::Response::ok("text/plain; charset=utf-8", report) } else { http::Response::unavailable(report) };
+    }
+
+    if path == "/metrics" {
+        return http::Response::ok(METRICS_CONTENT_TYPE, metrics.render());
+    }
+
+    let history = history.lock().await;
+    if path == "/feed.atom" {
+        return http::Response::ok(ATOM_CONTENT_TYPE, history.combined_feed());
+    }
+
+    path.strip_prefix("/courses/").and_then(|p| p.strip_suffix(".atom")).and_then(|id| id.parse().ok())
+        .and_then(|id| history.course_feed(id))
+        .map(|feed| http::Response::ok(ATOM_CONTENT_TYPE, feed))
+        .unwrap_or_else(http::Response::not_found)
+}
+
+// Every watched course once, with the account of the lowest channel id watching it so it doesn't change between polls
+fn polled_courses(subscribers: &HashMap<ChannelId, Subscription>) -> Vec<(u32, String)> {
+    let mut channels = subscribers.keys().collect::<Vec<_>>();
+    channels.sort();
+
+    let mut courses: Vec<(u32, String)> = Vec::new();
+    for subscription in channels.into_iter().map(|c| &subscribers[c]) {
+        for course in &subscription.courses {
+            if courses.iter().all(|(id, _)| *id != course.id()) {
+                courses.push((course.id(), subscription.account.clone()));
+            }
         }
-    } 
+    }
+    courses
 }
 
-fn get_resp(conf: &Conf) -> &str {
-    conf.responses.get(thread_rng().gen_range(0..conf.responses.len())).expect("Expected at least one response text")
+fn get_resp(conf: &Conf, channel: ChannelId) -> &str {
+    random_resp(conf.responses(channel))
+}
+
+#[test]
+fn test_polled_courses() {
+    let subscription = |account: &str, ids: &[u32]| Subscription {
+        account: account.to_string(),
+        courses: ids.iter().map(|id| MoodleCourseData::example(*id)).collect(),
+        configured: Vec::new()
+    };
+    let mut subscribers = HashMap::new();
+    subscribers.insert(ChannelId(3), subscription("groupb", &[10, 12]));
+    subscribers.insert(ChannelId(1), subscription("default", &[11, 10]));
+    subscribers.insert(ChannelId(2), subscription("default", &[]));
+
+    // Each course once, with the account of the lowest channel watching it
+    assert_eq!(polled_courses(&subscribers), vec![
+        (11, "default".to_string()),
+        (10, "default".to_string()),
+        (12, "groupb".to_string())
+    ]);
 }
 
-struct Conf {
-    discord_token: String,
-    discord_client_id: String,
-    discord_channel_id: ChannelId,
-    course_ids: Vec<String>,
-    responses: Vec<String>
+#[tokio::test]
+async fn test_route_feeds() {
+    let history = Mutex::new(History::new());
+    history.lock().await.record(&ChangeEvent::example());
+    let metrics = Metrics::new();
+    let health = std::sync::Mutex::new(Health::new(Duration::from_secs(3 * POLL_INTERVAL)));
+
+    let course = route(&history, &metrics, &health, "/courses/42.atom").await;
+    assert_eq!(course.status(), 200);
+    assert!(course.body().contains("<title>Poodle: Test</title>"));
+    assert!(course.body().contains("Updated: &quot;Sheet 1&quot;"));
+
+    assert_eq!(route(&history, &metrics, &health, "/feed.atom").await.status(), 200);
+    assert_eq!(route(&history, &metrics, &health, "/courses/43.atom").await.status(), 404);
+    assert_eq!(route(&history, &metrics, &health, "/courses/x.atom").await.status(), 404);
 }
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+
+pub const METRICS_CONTENT_TYPE: &str = prometheus::TEXT_FORMAT;
+
+// Commands counted by name, anything else the bot is mentioned with is counted as "unknown"
+pub const COMMANDS: &[&str] = &["watch", "unwatch", "timer", "makegroups", "account", "export", "status", "send"];
+
+// Cheap to clone, all clones update the same metrics
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    moodle_fetches: IntCounterVec,
+    moodle_fetch_seconds: Histogram,
+    moodle_logins: IntCounterVec,
+    changes: IntCounterVec,
+    discord_send_errors: IntCounter,
+    commands: IntCounterVec
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let metrics = Self {
+            registry: Registry::new(),
+            moodle_fetches: IntCounterVec::new(Opts::new("poodle_moodle_fetches_total", "Moodle pages fetched"), &["result"]).unwrap(),
+            moodle_fetch_seconds: Histogram::with_opts(HistogramOpts::new("poodle_moodle_fetch_duration_seconds", "Time taken to fetch a Moodle page, including logins")
+                .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0])).unwrap(),
+            moodle_logins: IntCounterVec::new(Opts::new("poodle_moodle_logins_total", "Moodle login attempts"), &["result"]).unwrap(),
+            changes: IntCounterVec::new(Opts::new("poodle_changes_total", "Changes detected in watched courses"), &["course"]).unwrap(),
+            discord_send_errors: IntCounter::new("poodle_discord_send_errors_total", "Discord messages that failed to send").unwrap(),
+            commands: IntCounterVec::new(Opts::new("poodle_commands_total", "Bot commands received"), &["command"]).unwrap()
+        };
+
+        metrics.registry.register(Box::new(metrics.moodle_fetches.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.moodle_fetch_seconds.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.moodle_logins.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.changes.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.discord_send_errors.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.commands.clone())).unwrap();
+
+        metrics
+    }
+
+    pub fn moodle_fetch(&self, elapsed: Duration, ok: bool) {
+        self.moodle_fetches.with_label_values(&[result(ok)]).inc();
+        self.moodle_fetch_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn moodle_login(&self, ok: bool) {
+        self.moodle_logins.with_label_values(&[result(ok)]).inc();
+    }
+
+    pub fn changes(&self, course: u32, count: usize) {
+        self.changes.with_label_values(&[&course.to_string()]).inc_by(count as u64);
+    }
+
+    pub fn discord_send_error(&self) {
+        self.discord_send_errors.inc();
+    }
+
+    pub fn command(&self, name: &str) {
+        let name = if COMMANDS.contains(&name) { name } else { "unknown" };
+        self.commands.with_label_values(&[name]).inc();
+    }
+
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf).expect("Failed to encode metrics");
+        String::from_utf8(buf).expect("Metrics are not UTF-8")
+    }
+}
+
+fn result(ok: bool) -> &'static str {
+    if ok { "ok" } else { "error" }
+}
+
+#[test]
+fn test_metrics_render() {
+    let metrics = Metrics::new();
+    metrics.command("watch");
+    metrics.command("<@123>");
+    metrics.changes(42, 3);
+
+    let text = metrics.render();
+    assert!(text.contains("poodle_commands_total{command=\"watch\"} 1"));
+    assert!(text.contains("poodle_commands_total{command=\"unknown\"} 1"));
+    assert!(text.contains("poodle_changes_total{course=\"42\"} 3"));
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use tracing::{info, warn};
+
+use crate::moodle::*;
+
+const MANIFEST: &str = ".poodle-mirror";
+
+// Mirrors course files into <root>/<course>/<section>/<file>, remembering the URL each file was
+// downloaded from so that only files with a new revision are fetched again
+pub struct Mirror {
+    root: PathBuf
+}
+
+// Files that failed are left out of the manifest, so the next sync tries them again
+#[derive(Debug)]
+pub struct MirrorSync {
+    pub downloaded: usize,
+    pub failed: usize
+}
+
+impl Mirror {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into()
+        }
+    }
+
+    // The context is locked per request rather than for the whole sync, so commands using the account aren't held up by downloads
+    pub async fn sync(&self, context: &Mutex<MoodleContext>, course: &MoodleCourseData) -> io::Result<MirrorSync> {
+        let dir = self.root.join(sanitize(&format!("{} ({})", course.name(), course.id())));
+        fs::create_dir_all(&dir).await?;
+
+        let manifest_path = dir.join(MANIFEST);
+        let mut manifest = read_manifest(&manifest_path).await;
+
+        let (files, failed) = context.lock().await.course_files(course).await;
+        let mut sync = MirrorSync {
+            downloaded: 0,
+            failed
+        };
+
+        let paths = file_paths(&files.iter().map(|(id, location, _)| (*id, location.clone())).collect::<Vec<_>>());
+        for ((_, _, file), path) in files.into_iter().zip(paths) {
+            let key = path.to_string_lossy().to_string();
+            let target = dir.join(&path);
+
+            if manifest.get(&key).map(|url| url == file.url()).unwrap_or(false) && target.exists() {
+                continue;
+            }
+
+            let res = context.lock().await.download(file.url()).await;
+            match res {
+                Ok(bytes) => {
+                    if let Some(parent) = target.parent() {
+                        fs::create_dir_all(parent).await?;
+                    }
+                    fs::write(&target, bytes).await?;
+
+                    info!(course = course.id(), "Mirrored {}", target.display());
+                    manifest.insert(key, file.url().to_string());
+                    sync.downloaded += 1;
+                },
+                Err(e) => {
+                    warn!(course = course.id(), "Failed to download {}: {}", file.url(), e);
+                    sync.failed += 1;
+                }
+            }
+        }
+
+        write_manifest(&manifest_path, &manifest).await?;
+        Ok(sync)
+    }
+}
+
+async fn read_manifest(path: &Path) -> HashMap<String, String> {
+    match fs::read_to_string(path).await {
+        Ok(text) => text.lines().filter_map(|l| {
+            let mut parts = l.splitn(2, '\t');
+            Some((parts.next()?.to_string(), parts.next()?.to_string()))
+        }).collect(),
+        Err(_) => HashMap::new()
+    }
+}
+
+async fn write_manifest(path: &Path, manifest: &HashMap<String, String>) -> io::Result<()> {
+    let mut entries = manifest.iter().map(|(path, url)| format!("{}\t{}\n", path, url)).collect::<Vec<_>>();
+    entries.sort();
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, entries.concat()).await?;
+    fs::rename(&tmp, path).await
+}
+
+// Paths below the course directory. Files that would end up at the same path, like two resources of the same name in one section,
+// get their activity id added, so they neither overwrite each other nor take turns being downloaded again.
+fn file_paths(locations: &[(u32, Vec<String>)]) -> Vec<PathBuf> {
+    let path = |location: &[String]| location.iter().filter(|c| !c.is_empty()).map(|c| sanitize(c)).collect::<PathBuf>();
+    let paths = locations.iter().map(|(_, location)| path(location)).collect::<Vec<_>>();
+
+    paths.iter().zip(locations).map(|(p, (id, location))| {
+        if paths.iter().filter(|other| *other == p).count() == 1 {
+            return p.clone();
+        }
+
+        let mut location = location.clone();
+        if let Some(name) = location.last_mut() {
+            *name = match name.rfind('.') {
+                Some(i) if i > 0 => format!("{} ({}){}", &name[..i], id, &name[i..]),
+                _ => format!("{} ({})", name, id)
+            };
+        }
+        path(&location)
+    }).collect()
+}
+
+fn sanitize(name: &str) -> String {
+    let name = name.trim().replace(|c: char| c == '/' || c == '\\' || c == '\t' || c.is_control(), "_");
+    match name.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => name
+    }
+}
+
+#[test]
+fn test_mirror_sanitize() {
+    assert_eq!(sanitize(" Week 1/2 "), "Week 1_2");
+    assert_eq!(sanitize(".."), "_");
+    assert_eq!(sanitize("Sheet 3.pdf"), "Sheet 3.pdf");
+}
+
+#[test]
+fn test_mirror_file_paths() {
+    let location = |id: u32, parts: &[&str]| (id, parts.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    let paths = file_paths(&[
+        location(1, &["Week 1", "Sheet.pdf"]),
+        location(2, &["Week 1", "Sheet.pdf"]),
+        location(3, &["Week 2", "Sheet.pdf"]),
+        location(4, &["Week 1", "Notes"]),
+        location(5, &["Week 1", "Notes"]),
+        location(6, &["", "Slides", "Sheet.pdf"])
+    ]);
+
+    assert_eq!(paths, vec![
+        PathBuf::from("Week 1/Sheet (1).pdf"),
+        PathBuf::from("Week 1/Sheet (2).pdf"),
+        PathBuf::from("Week 2/Sheet.pdf"),
+        PathBuf::from("Week 1/Notes (4)"),
+        PathBuf::from("Week 1/Notes (5)"),
+        PathBuf::from("Slides/Sheet.pdf")
+    ]);
+}
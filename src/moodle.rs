@@ -1,13 +1,36 @@
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::time::Instant;
 
 use kuchiki::*;
+use kuchiki::iter::NodeEdge;
 use kuchiki::traits::*;
 
 use html_diff::{get_differences, Difference};
 
+use percent_encoding::percent_decode_str;
+
+use sha2::{Digest, Sha256};
+
+use serde::Serialize;
+
+use tracing::{debug, instrument, warn};
+
+use crate::metrics::Metrics;
+
 pub struct MoodleContext {
     auth: MoodleAuthConf,
-    state: MoodleState
+    state: MoodleState,
+    file_tracking: FileTracking,
+    metrics: Option<Metrics>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileTracking {
+    Off,
+    Revision,
+    Hash
 }
 
 pub enum MoodleState {
@@ -16,38 +39,73 @@ pub enum MoodleState {
 }
 
 impl MoodleContext {
-    pub fn new(auth: MoodleAuthConf) -> Self {
+    pub fn new(auth: MoodleAuthConf, file_tracking: FileTracking) -> Self {
         Self {
             auth,
-            state: MoodleState::Unknown
+            state: MoodleState::Unknown,
+            file_tracking,
+            metrics: None
         }
     }
 
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = Some(metrics);
+    }
+
     pub async fn get(&mut self, id: u32) -> Result<MoodleCourseData, MoodleErr> {
-        let client = self.verify_state().await?;
+        self.get_with_origin(id, None).await
+    }
 
+    // The origin lets unchanged folder files keep their known versions instead of requesting them again
+    #[instrument(skip(self, origin), fields(course = id))]
+    async fn get_with_origin(&mut self, id: u32, origin: Option<&MoodleCourseData>) -> Result<MoodleCourseData, MoodleErr> {
         let url = format!("https://www.moodle.tum.de/course/view.php?id={}", id);
-        let resp = client.get(&url).send().await.or(Err(MoodleErr::Network))?;
-        if resp.status() != 200 {
-            return Err(MoodleErr::CourseNotFound);
-        }
-        let text = resp.text().await.or(Err(MoodleErr::Network))?;
-        let html = parse_html().one(text);
+        let text = match self.fetch(&url).await {
+            Err(MoodleErr::Status{ status, .. }) if status == 404 => return Err(MoodleErr::CourseNotFound{ id }),
+            res => res?
+        };
 
-        let mut content = String::new();
-        let mut name = String::new();
+        let (content, name, mut activities) = parse_course_page(text);
 
-        for element in html.descendants().elements() {
-            let id_attr = element.attributes.borrow().get("id").unwrap_or("").to_string();
+        for activity in activities.iter_mut().filter(|a| a.kind == "page" && a.is_available()) {
+            if let Some(url) = activity.url.clone() {
+                match self.fetch(&url).await {
+                    Ok(text) => activity.text = Some(parse_page_text(&text)),
+                    Err(e) => {
+                        // Keeping the known text lets the next successful fetch still report an edit made meanwhile
+                        warn!(activity = activity.id, "Failed to fetch page: {}", e);
+                        activity.text = origin.and_then(|o| o.activities.iter().find(|a| a.id == activity.id)).and_then(|a| a.text.clone());
+                    }
+                }
+            }
+        }
 
-            match &*element.name.local {
-                "div" if id_attr == "page-content" => {
-                    let mut content_buf: Vec<u8> = Vec::new();
-                    element.as_node().serialize(&mut content_buf).unwrap();
-                    content = String::from_utf8(content_buf).unwrap();
-                },
-                "h1" => name = element.text_contents(),
-                _ => ()
+        for activity in activities.iter_mut().filter(|a| a.kind == "folder" && a.is_available()) {
+            if let Some(url) = activity.url.clone() {
+                let known = origin.and_then(|o| o.activities.iter().find(|a| a.id == activity.id)).and_then(|a| a.files.clone());
+                match self.fetch_folder(&url, known.as_deref().unwrap_or_default()).await {
+                    Ok(files) => activity.files = Some(files),
+                    Err(e) => {
+                        // Keeping the known files avoids losing the baseline and inspecting every file again
+                        warn!(activity = activity.id, "Failed to fetch folder: {}", e);
+                        activity.files = known;
+                    }
+                }
+            }
+        }
+
+        if self.file_tracking != FileTracking::Off {
+            for activity in activities.iter_mut().filter(|a| a.kind == "resource" && a.is_available()) {
+                if let Some(url) = activity.url.clone() {
+                    let known = origin.and_then(|o| o.activities.iter().find(|a| a.id == activity.id)).and_then(|a| a.files.clone());
+                    match self.fetch_resource(&url, known.as_deref().unwrap_or_default()).await {
+                        Ok(file) => activity.files = file.map(|f| vec![f]),
+                        Err(e) => {
+                            warn!(activity = activity.id, "Failed to fetch resource: {}", e);
+                            activity.files = known;
+                        }
+                    }
+                }
             }
         }
 
@@ -56,62 +114,207 @@ impl MoodleContext {
                 id,
                 name,
                 url,
-                content
+                content,
+                activities
             })
         } else {
-            Err(MoodleErr::CourseNotFound)
+            Err(MoodleErr::CourseNotFound{ id })
         }
     }
 
-    pub async fn update(&mut self, origin: &mut MoodleCourseData) -> Result<Option<String>, MoodleErr> {
-        let target = self.get(origin.id()).await?;
-        if target.content() != origin.content() {
-            let diff = origin.user_diff(&target);
-            origin.content = target.content;
-            Ok(diff)
-        } else {
-            Ok(None)
+    pub async fn update(&mut self, origin: &mut MoodleCourseData) -> Result<Vec<MoodleChange>, MoodleErr> {
+        let target = self.get_with_origin(origin.id(), Some(origin)).await?;
+        let changes = origin.changes(&target);
+        *origin = target;
+        Ok(changes)
+    }
+
+    // Fetches a page with the logged in session, logging in again if Moodle served a login or guest page instead
+    async fn fetch(&mut self, url: &str) -> Result<String, MoodleErr> {
+        let start = Instant::now();
+        let res = self.fetch_page(url).await;
+        if let Some(metrics) = &self.metrics {
+            metrics.moodle_fetch(start.elapsed(), res.is_ok());
         }
+        res
     }
 
-    async fn verify_state(&mut self) -> Result<reqwest::Client, MoodleErr> {
-        match &mut self.state {
-            MoodleState::Unknown => {
-                for _ in 0..3 {
-                    match self.try_login().await {
-                        Ok(client) => {
-                            self.state = MoodleState::MaybeLoggedIn{
-                                client: client.clone()
-                            };
-                            return Ok(client);
-                        },
-                        Err(e) => eprintln!("Login attempt failed: {:?}", e)
+    async fn fetch_page(&mut self, url: &str) -> Result<String, MoodleErr> {
+        for _ in 0..2 {
+            let client = self.verify_state().await?;
+
+            let resp = client.get(url).send().await.map_err(|e| MoodleErr::network(url, e))?;
+            if resp.status() != 200 {
+                return Err(MoodleErr::Status{ url: url.to_string(), status: resp.status() });
+            }
+            let final_url = resp.url().clone();
+            let text = resp.text().await.map_err(|e| MoodleErr::network(url, e))?;
+
+            if !session_expired(&final_url, &text) {
+                return Ok(text);
+            }
+
+            warn!("Session expired while fetching {}, logging in again", url);
+            self.state = MoodleState::Unknown;
+        }
+
+        Err(MoodleErr::SessionExpired{ url: url.to_string() })
+    }
+
+    async fn fetch_folder(&mut self, url: &str, known: &[MoodleFile]) -> Result<Vec<MoodleFile>, MoodleErr> {
+        let mut files = parse_folder_files(&self.fetch(url).await?);
+
+        // Any change to a folder bumps the revision in all of its file URLs, so only then are the files checked again
+        for _ in 0..2 {
+            let client = self.verify_state().await?;
+            let mut expired = false;
+
+            for file in files.iter_mut() {
+                if let Some(k) = known.iter().find(|k| k.url == file.url) {
+                    *file = k.clone();
+                    continue;
+                }
+
+                match inspect_file(&client, &file.url, self.file_tracking == FileTracking::Hash).await? {
+                    Some(info) => {
+                        file.version = info.version;
+                        file.size = info.size;
+                        file.hash = info.hash;
+                    },
+                    None => {
+                        expired = true;
+                        break;
                     }
                 }
+            }
 
-                Err(MoodleErr::Login)
-            },
-            MoodleState::MaybeLoggedIn{ client } => {
-                let resp = client.get("https://www.moodle.tum.de/").send().await.or(Err(MoodleErr::Network))?;
-                if resp.status() == 200 {
-                    Ok(client.clone())
-                } else {
-                    for _ in 0..3 {
-                        match self.try_login().await {
-                            Ok(client) => {
-                                self.state = MoodleState::MaybeLoggedIn{
-                                    client: client.clone()
-                                };
-                                return Ok(client);
-                            },
-                            Err(e) => eprintln!("Login attempt failed: {:?}", e)
-                        }
+            if !expired {
+                return Ok(files);
+            }
+
+            warn!("Session expired while fetching {}, logging in again", url);
+            self.state = MoodleState::Unknown;
+        }
+
+        Err(MoodleErr::SessionExpired{ url: url.to_string() })
+    }
+
+    // Resolves a resource to the pluginfile URL it serves, which carries the file revision
+    async fn fetch_resource(&mut self, url: &str, known: &[MoodleFile]) -> Result<Option<MoodleFile>, MoodleErr> {
+        for _ in 0..2 {
+            let client = self.verify_state().await?;
+
+            let resp = client.head(url).send().await.map_err(|e| MoodleErr::network(url, e))?;
+            if !login_redirect(resp.url()) {
+                let mut file_url = resp.url().to_string();
+
+                // Resources displayed embedded or in a popup only link to the file
+                if !file_url.contains("/pluginfile.php/") {
+                    match find_file_link(&self.fetch(url).await?) {
+                        Some(link) => file_url = link,
+                        None => return Ok(None)
                     }
+                }
+
+                if let Some(k) = known.iter().find(|k| k.url == file_url) {
+                    return Ok(Some(k.clone()));
+                }
 
-                    Err(MoodleErr::Login)
+                if let Some(file) = inspect_file(&client, &file_url, self.file_tracking == FileTracking::Hash).await? {
+                    return Ok(Some(file));
                 }
             }
+
+            warn!("Session expired while fetching {}, logging in again", url);
+            self.state = MoodleState::Unknown;
+        }
+
+        Err(MoodleErr::SessionExpired{ url: url.to_string() })
+    }
+
+    // Every file of a course with its location below the course as section, folder and file name, and the number of resources
+    // that couldn't be resolved. Untracked resources are resolved again each time, which picks up the revision of re-uploads.
+    pub async fn course_files(&mut self, course: &MoodleCourseData) -> (Vec<(u32, Vec<String>, MoodleFile)>, usize) {
+        let mut files = Vec::new();
+        let mut failed = 0;
+
+        for activity in course.activities.iter().filter(|a| a.is_available()) {
+            match (activity.kind.as_str(), &activity.files, &activity.url) {
+                ("folder", Some(folder), _) => for file in folder {
+                    files.push((activity.id, vec![activity.section.clone(), activity.name.clone(), file.name.clone()], file.clone()));
+                },
+                ("resource", Some(resource), _) => for file in resource {
+                    files.push((activity.id, vec![activity.section.clone(), file.name.clone()], file.clone()));
+                },
+                ("resource", None, Some(url)) => match self.fetch_resource(url, &[]).await {
+                    Ok(Some(file)) => files.push((activity.id, vec![activity.section.clone(), file.name.clone()], file)),
+                    Ok(None) => (),
+                    Err(e) => {
+                        warn!(course = course.id, activity = activity.id, "Failed to fetch resource: {}", e);
+                        failed += 1;
+                    }
+                },
+                _ => ()
+            }
         }
+
+        (files, failed)
+    }
+
+    pub async fn download(&mut self, url: &str) -> Result<Vec<u8>, MoodleErr> {
+        for _ in 0..2 {
+            let client = self.verify_state().await?;
+
+            let resp = client.get(url).send().await.map_err(|e| MoodleErr::network(url, e))?;
+            if resp.status() != 200 {
+                return Err(MoodleErr::Status{ url: url.to_string(), status: resp.status() });
+            }
+
+            if !login_redirect(resp.url()) {
+                return Ok(resp.bytes().await.map_err(|e| MoodleErr::network(url, e))?.to_vec());
+            }
+
+            warn!("Session expired while fetching {}, logging in again", url);
+            self.state = MoodleState::Unknown;
+        }
+
+        Err(MoodleErr::SessionExpired{ url: url.to_string() })
+    }
+
+    // Logs in unless there already is a session
+    pub async fn login(&mut self) -> Result<(), MoodleErr> {
+        self.verify_state().await.map(|_| ())
+    }
+
+    // An existing session is trusted, every request checks its response for an expired session and resets the state
+    async fn verify_state(&mut self) -> Result<reqwest::Client, MoodleErr> {
+        if let MoodleState::MaybeLoggedIn{ client } = &self.state {
+            return Ok(client.clone());
+        }
+
+        let mut last_err = None;
+        for _ in 0..3 {
+            let res = self.try_login().await;
+            if let Some(metrics) = &self.metrics {
+                metrics.moodle_login(res.is_ok());
+            }
+
+            match res {
+                Ok(client) => {
+                    self.state = MoodleState::MaybeLoggedIn{
+                        client: client.clone()
+                    };
+                    return Ok(client);
+                },
+                Err(e) => {
+                    warn!("Login attempt failed: {}", e);
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        self.state = MoodleState::Unknown;
+        Err(MoodleErr::Login{ attempts: 3, last: Box::new(last_err.unwrap()) })
     }
 
     async fn try_login(&self) -> Result<reqwest::Client, MoodleErr> {
@@ -119,20 +322,22 @@ impl MoodleContext {
             MoodleAuthConf::ShibbolethUser(user, pass) => {
                 let client = reqwest::ClientBuilder::new()
                     .cookie_store(true)
-                    .build().unwrap();
+                    .build().map_err(|e| MoodleErr::network("", e))?;
 
-                let resp = client.get("https://www.moodle.tum.de/Shibboleth.sso/Login?providerId=https%3A%2F%2Ftumidp.lrz.de%2Fidp%2Fshibboleth&target=https%3A%2F%2Fwww.moodle.tum.de%2Fauth%2Fshibboleth%2Findex.php")
+                let url = "https://www.moodle.tum.de/Shibboleth.sso/Login?providerId=https%3A%2F%2Ftumidp.lrz.de%2Fidp%2Fshibboleth&target=https%3A%2F%2Fwww.moodle.tum.de%2Fauth%2Fshibboleth%2Findex.php";
+                let resp = client.get(url)
                     .header("Referer", "https://www.moodle.tum.de/")
-                    .send().await.or(Err(MoodleErr::Network))?;
-                let text = resp.text().await.or(Err(MoodleErr::Network))?;
+                    .send().await.map_err(|e| MoodleErr::network(url, e))?;
+                let text = resp.text().await.map_err(|e| MoodleErr::network(url, e))?;
 
-                let url = format!("https://login.tum.de{}", text.split("form action=\"").collect::<Vec<_>>()[1].split("\"").collect::<Vec<_>>()[0]);
+                let form_action = text.split("form action=\"").collect::<Vec<_>>().get(1).ok_or(MoodleErr::Auth{ step: LoginStep::IdpRedirect })?.split("\"").collect::<Vec<_>>()[0];
+                let url = format!("https://login.tum.de{}", form_action);
 
                 let resp = client.get(&url)
-                    .send().await.or(Err(MoodleErr::Network))?;
-                let text = resp.text().await.or(Err(MoodleErr::Network))?;
+                    .send().await.map_err(|e| MoodleErr::network(&url, e))?;
+                let text = resp.text().await.map_err(|e| MoodleErr::network(&url, e))?;
 
-                let csrf_token = text.split("name=\"csrf_token\" value=\"").collect::<Vec<_>>().get(1).ok_or(MoodleErr::Auth)?.split("\"").collect::<Vec<_>>()[0];
+                let csrf_token = text.split("name=\"csrf_token\" value=\"").collect::<Vec<_>>().get(1).ok_or(MoodleErr::Auth{ step: LoginStep::LoginForm })?.split("\"").collect::<Vec<_>>()[0];
 
                 let mut form = HashMap::new();
                 form.insert("csrf_token", csrf_token);
@@ -142,19 +347,23 @@ impl MoodleContext {
                 form.insert("_eventId_proceed", "");
                 let resp = client.post(&url)
                     .form(&form)
-                    .send().await.or(Err(MoodleErr::Network))?;
-                let text = resp.text().await.or(Err(MoodleErr::Network))?;
+                    .send().await.map_err(|e| MoodleErr::network(&url, e))?;
+                let text = resp.text().await.map_err(|e| MoodleErr::network(&url, e))?;
 
-                let relay_state = text.split("name=\"RelayState\" value=\"cookie&#x3a;").collect::<Vec<_>>().get(1).ok_or(MoodleErr::Auth)?.split("\"").collect::<Vec<_>>()[0];
+                let relay_state = text.split("name=\"RelayState\" value=\"cookie&#x3a;").collect::<Vec<_>>().get(1).ok_or(MoodleErr::Auth{ step: LoginStep::Credentials })?.split("\"").collect::<Vec<_>>()[0];
                 let relay_state = format!("cookie:{}", relay_state);
-                let saml_resp = text.split("name=\"SAMLResponse\" value=\"").collect::<Vec<_>>().get(1).ok_or(MoodleErr::Auth)?.split("\"").collect::<Vec<_>>()[0].to_string();
+                let saml_resp = text.split("name=\"SAMLResponse\" value=\"").collect::<Vec<_>>().get(1).ok_or(MoodleErr::Auth{ step: LoginStep::Credentials })?.split("\"").collect::<Vec<_>>()[0].to_string();
 
+                let url = "https://www.moodle.tum.de/Shibboleth.sso/SAML2/POST";
                 let mut form = HashMap::new();
                 form.insert("RelayState", relay_state);
                 form.insert("SAMLResponse", saml_resp);
-                client.post("https://www.moodle.tum.de/Shibboleth.sso/SAML2/POST")
+                let resp = client.post(url)
                     .form(&form)
-                    .send().await.or(Err(MoodleErr::Network))?;
+                    .send().await.map_err(|e| MoodleErr::network(url, e))?;
+                if !resp.status().is_success() {
+                    return Err(MoodleErr::Status{ url: url.to_string(), status: resp.status() });
+                }
 
                 Ok(client)
             }
@@ -162,20 +371,364 @@ impl MoodleContext {
     }
 }
 
-#[derive(Clone, Debug)]
+// Moodle answers 200 for login redirects and the guest front page, so the status code alone is not enough
+fn session_expired(url: &reqwest::Url, text: &str) -> bool {
+    if login_redirect(url) {
+        return true;
+    }
+
+    let html = parse_html().one(text);
+    let mut user_menu = false;
+
+    for element in html.descendants().elements() {
+        let class_attr = element.attributes.borrow().get("class").unwrap_or("").to_string();
+        let classes = class_attr.split_whitespace().collect::<Vec<_>>();
+
+        match &*element.name.local {
+            "body" if classes.contains(&"notloggedin") => return true,
+            "div" if classes.contains(&"logininfo") => {
+                let text = element.text_contents().to_lowercase();
+                if text.contains("guest") || text.contains("gast") {
+                    return true;
+                }
+            },
+            "span" if classes.contains(&"userbutton") => user_menu = true,
+            _ => ()
+        }
+    }
+
+    !user_menu
+}
+
+// Returns the page content, course name and activities of a course page. Saved fragments without the
+// surrounding page are taken as the content as a whole. Not async as the DOM is not Send.
+fn parse_course_page(text: String) -> (String, String, Vec<MoodleActivity>) {
+    let html = parse_html().one(text);
+
+    let mut content = None;
+    let mut name = String::new();
+    let mut activities = Vec::new();
+
+    for element in html.descendants().elements() {
+        let id_attr = element.attributes.borrow().get("id").unwrap_or("").to_string();
+
+        match &*element.name.local {
+            "div" if id_attr == "page-content" => {
+                let mut content_buf: Vec<u8> = Vec::new();
+                element.as_node().serialize(&mut content_buf).unwrap();
+                content = Some(String::from_utf8(content_buf).unwrap());
+                activities = parse_activities(element.as_node());
+            },
+            "h1" => name = element.text_contents(),
+            _ => ()
+        }
+    }
+
+    let content = content.unwrap_or_else(|| {
+        activities = parse_activities(&html);
+        html.to_string()
+    });
+
+    (content, name, activities)
+}
+
+// Returns None if Moodle redirected to the login instead of serving the file
+async fn inspect_file(client: &reqwest::Client, url: &str, hash: bool) -> Result<Option<MoodleFile>, MoodleErr> {
+    let resp = client.head(url).send().await.map_err(|e| MoodleErr::network(url, e))?;
+    if login_redirect(resp.url()) {
+        return Ok(None);
+    }
+
+    let final_url = resp.url().to_string();
+    let headers = resp.headers();
+    let version = headers.get("etag").or_else(|| headers.get("last-modified")).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+    let size = headers.get("content-length").and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok());
+
+    let hash = if hash {
+        let resp = client.get(&final_url).send().await.map_err(|e| MoodleErr::network(&final_url, e))?;
+        let bytes = resp.bytes().await.map_err(|e| MoodleErr::network(&final_url, e))?;
+        Some(Sha256::digest(&bytes).iter().map(|b| format!("{:02x}", b)).collect())
+    } else {
+        None
+    };
+
+    Ok(Some(MoodleFile {
+        name: file_name(&final_url),
+        url: final_url,
+        version,
+        size,
+        hash
+    }))
+}
+
+fn file_name(url: &str) -> String {
+    let path = url.split('?').next().unwrap_or("");
+    let name = path.rsplit('/').next().unwrap_or("");
+    percent_decode_str(name).decode_utf8_lossy().to_string()
+}
+
+fn find_file_link(text: &str) -> Option<String> {
+    let html = parse_html().one(text);
+    let link = html.select_first("[role=main] a[href*=\"pluginfile.php\"]").ok()?;
+    let href = link.attributes.borrow().get("href").map(|h| h.to_string());
+    href
+}
+
+fn login_redirect(url: &reqwest::Url) -> bool {
+    let path = url.path();
+    url.host_str() != Some("www.moodle.tum.de") || path.starts_with("/login/") || path.starts_with("/Shibboleth.sso/") || path.starts_with("/auth/")
+}
+
+fn parse_activities(content: &NodeRef) -> Vec<MoodleActivity> {
+    let mut activities = Vec::new();
+
+    for activity in content.select("li.activity").unwrap() {
+        let id = match activity.attributes.borrow().get("id").and_then(|id| id.strip_prefix("module-")).and_then(|id| id.parse().ok()) {
+            Some(id) => id,
+            None => continue
+        };
+        let node = activity.as_node();
+        let kind = activity.attributes.borrow().get("class").unwrap_or("").split_whitespace().find_map(|c| c.strip_prefix("modtype_")).unwrap_or("").to_string();
+
+        let kind_name = node.select_first(".instancename .accesshide").map(|e| e.text_contents()).unwrap_or_default();
+        let name = match node.select_first(".instancename") {
+            Ok(e) => {
+                let text = e.text_contents();
+                clean_text(text.strip_suffix(&kind_name).unwrap_or(&text))
+            },
+            Err(_) => node.select_first(".contentwithoutlink").map(|e| clean_text(&e.text_contents()).chars().take(60).collect()).unwrap_or_default()
+        };
+
+        let text = match node.select_first(".contentwithoutlink") {
+            Ok(e) if kind == "label" => Some(text_lines(e.as_node()).join("\n")),
+            _ => None
+        };
+
+        let section = node.ancestors().elements().find(|e| &*e.name.local == "li" && e.attributes.borrow().get("class").unwrap_or("").split_whitespace().any(|c| c == "section")).map(|e| {
+            match e.as_node().select_first(".sectionname") {
+                Ok(name) => clean_text(&name.text_contents()),
+                Err(_) => e.attributes.borrow().get("aria-label").unwrap_or("").to_string()
+            }
+        }).unwrap_or_default();
+
+        let mut dates = Vec::new();
+        for date in node.select("[data-region=activity-dates] div, .activity-dates div").unwrap() {
+            let date = clean_text(&date.text_contents());
+            if !date.is_empty() && !dates.contains(&date) {
+                dates.push(date);
+            }
+        }
+
+        activities.push(MoodleActivity {
+            id,
+            name,
+            section,
+            kind_name: if !kind_name.is_empty() { clean_text(&kind_name) } else { kind.clone() },
+            kind,
+            url: node.select_first("a").ok().and_then(|e| e.attributes.borrow().get("href").map(|h| h.to_string())),
+            text,
+            files: None,
+            visible: node.select_first(".dimmed, .dimmed_text").is_err(),
+            restriction: node.select_first(".availabilityinfo").ok().map(|e| clean_text(&e.text_contents())),
+            dates
+        });
+    }
+
+    activities
+}
+
+fn parse_folder_files(text: &str) -> Vec<MoodleFile> {
+    let html = parse_html().one(text);
+    let mut files = Vec::new();
+
+    for link in html.select("a[href*=\"pluginfile.php\"]").unwrap() {
+        let url = link.attributes.borrow().get("href").unwrap_or("").to_string();
+        let name = match link.as_node().select_first(".fp-filename") {
+            Ok(e) => clean_text(&e.text_contents()),
+            Err(_) => clean_text(&link.text_contents())
+        };
+
+        if !name.is_empty() && files.iter().all(|f: &MoodleFile| f.url != url) {
+            files.push(MoodleFile {
+                name,
+                url,
+                version: None,
+                size: None,
+                hash: None
+            });
+        }
+    }
+
+    files
+}
+
+fn parse_page_text(text: &str) -> String {
+    let html = parse_html().one(text);
+    let main = html.select_first("[role=main] .generalbox").or_else(|_| html.select_first("[role=main]"));
+
+    match main {
+        Ok(main) => text_lines(main.as_node()).join("\n"),
+        Err(_) => String::new()
+    }
+}
+
+fn clean_text(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Text content split into lines at block elements, so changes can be reported per paragraph
+fn text_lines(node: &NodeRef) -> Vec<String> {
+    let mut text = String::new();
+
+    for edge in node.traverse() {
+        match edge {
+            NodeEdge::Start(n) => {
+                if let Some(t) = n.as_text() {
+                    text.push_str(&t.borrow());
+                } else if let Some(e) = n.as_element() {
+                    if &*e.name.local == "br" {
+                        text.push('\n');
+                    }
+                }
+            },
+            NodeEdge::End(n) => {
+                if let Some("p" | "div" | "li" | "tr" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6") = n.as_element().map(|e| &*e.name.local) {
+                    text.push('\n');
+                }
+            }
+        }
+    }
+
+    text.lines().map(clean_text).filter(|l| !l.is_empty()).collect()
+}
+
+fn excerpt(origin: &str, target: &str) -> String {
+    let origin = origin.lines().collect::<Vec<_>>();
+    let added = target.lines().filter(|l| !origin.contains(l)).take(3).map(|l| {
+        if l.chars().count() > 200 {
+            format!("> {}...", l.chars().take(200).collect::<String>())
+        } else {
+            format!("> {}", l)
+        }
+    }).collect::<Vec<_>>();
+
+    if !added.is_empty() {
+        added.join("\n")
+    } else {
+        "> (text removed)".to_string()
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct MoodleCourseData {
     id: u32,
     name: String,
     url: String,
-    content: String
+    #[serde(skip)]
+    content: String,
+    activities: Vec<MoodleActivity>
 }
 
 impl MoodleCourseData {
-    #[allow(dead_code)]
+    // For course pages saved to disk, only what is on the page itself is known
+    pub fn from_html(url: &str, text: String) -> Self {
+        let (content, name, activities) = parse_course_page(text);
+
+        Self {
+            id: 0,
+            name,
+            url: url.to_string(),
+            content,
+            activities
+        }
+    }
+
+    // A course with just the given activities, as the diff tests need
+    #[cfg(test)]
+    fn with_activities(activities: Vec<MoodleActivity>) -> Self {
+        Self {
+            id: 0,
+            name: "Test".to_string(),
+            url: "https://example.com".to_string(),
+            content: String::new(),
+            activities
+        }
+    }
+
+    // An empty course with the given id, for tests outside this module
+    #[cfg(test)]
+    pub fn example(id: u32) -> Self {
+        Self {
+            id,
+            ..Self::with_activities(Vec::new())
+        }
+    }
+
     pub fn user_diff(&self, other: &MoodleCourseData) -> Option<String> {
+        let changes = self.changes(other);
+
+        if !changes.is_empty() {
+            Some(changes.iter().map(|c| format!("{}\n", c)).collect())
+        } else {
+            None
+        }
+    }
+
+    pub fn changes(&self, other: &MoodleCourseData) -> Vec<MoodleChange> {
+        // An activity is new if its id wasn't there before, recurring names like "Slides" don't make it known. The HTML diff
+        // is only left for pages without recognisable activities.
+        let mut changes = if self.activities.is_empty() && other.activities.is_empty() {
+            self.uploads(other)
+        } else {
+            other.activities.iter().filter(|a| self.activities.iter().all(|o| o.id != a.id))
+                .map(|a| MoodleChange::Uploaded{ kind: a.kind_name.clone(), name: a.name.clone() }).collect()
+        };
+
+        for activity in &other.activities {
+            if let Some(previous) = self.activities.iter().find(|a| a.id == activity.id) {
+                if !previous.is_available() && activity.is_available() {
+                    changes.push(MoodleChange::Available{ kind: activity.kind_name.clone(), name: activity.name.clone() });
+                }
+
+                if let (Some(origin), Some(target)) = (&previous.text, &activity.text) {
+                    if origin != target {
+                        changes.push(MoodleChange::TextChanged{ kind: activity.kind_name.clone(), name: activity.name.clone(), excerpt: excerpt(origin, target) });
+                    }
+                }
+
+                if let (Some(origin), Some(target)) = (&previous.files, &activity.files) {
+                    if activity.kind == "resource" {
+                        if let (Some(o), Some(t)) = (origin.first(), target.first()) {
+                            if o.replaced_by(t) {
+                                changes.push(MoodleChange::FileUpdated{ name: t.name.clone() });
+                            }
+                        }
+                        continue;
+                    }
+
+                    let added = target.iter().filter(|f| origin.iter().all(|o| o.name != f.name)).map(|f| f.name.clone()).collect::<Vec<_>>();
+                    let removed = origin.iter().filter(|o| target.iter().all(|f| f.name != o.name)).map(|o| o.name.clone()).collect::<Vec<_>>();
+                    let replaced = target.iter().filter(|f| origin.iter().any(|o| o.name == f.name && o.replaced_by(f)))
+                        .map(|f| f.name.clone()).collect::<Vec<_>>();
+
+                    if !added.is_empty() || !removed.is_empty() || !replaced.is_empty() {
+                        changes.push(MoodleChange::FolderChanged{ name: activity.name.clone(), added, removed, replaced });
+                    }
+                }
+            }
+        }
+
+        changes
+    }
+
+    fn uploads(&self, other: &MoodleCourseData) -> Vec<MoodleChange> {
+        if self.content() == other.content() {
+            return Vec::new();
+        }
+
         let change = get_differences(&self.content, &other.content);
 
-        let mut summary = String::new();
+        let mut uploads = Vec::new();
 
         for c in change {
             match c {
@@ -196,22 +749,22 @@ impl MoodleCourseData {
                         }
 
                         if content_name != "" && content_type != "" {
-                            summary.push_str(&format!("New \"{}\" uploaded: \"{}\"\n", &content_type[1..], &content_name[0..(content_name.len() - content_type.len())]));
+                            uploads.push(MoodleChange::Uploaded{
+                                kind: content_type[1..].to_string(),
+                                name: content_name[0..(content_name.len() - content_type.len())].to_string()
+                            });
 
                         } else {
-                            println!("Unrecognised change in course {}:\n{}\n-----", self.id, e.element_content);
+                            debug!(course = self.id, bytes = e.element_content.len(), "Unrecognised change");
                         }
                     }
                 },
                 _ => ()
             }
         }
-        
-        if summary != "" {
-            Some(summary)
-        } else {
-            None
-        }
+
+
+        uploads
     }
 
     pub fn id(&self) -> u32 {
@@ -229,13 +782,153 @@ impl MoodleCourseData {
     pub fn content(&self) -> &str {
         &self.content
     }
+
+    pub fn activities(&self) -> &[MoodleActivity] {
+        &self.activities
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MoodleActivity {
+    id: u32,
+    name: String,
+    section: String,
+    kind: String,
+    kind_name: String,
+    url: Option<String>,
+    text: Option<String>,
+    files: Option<Vec<MoodleFile>>,
+    visible: bool,
+    restriction: Option<String>,
+    dates: Vec<String>
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MoodleFile {
+    name: String,
+    url: String,
+    version: Option<String>,
+    size: Option<u64>,
+    hash: Option<String>
+}
+
+impl MoodleFile {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // Compares by the strongest identifier both sides have: content hash, then ETag or modification date, then size
+    fn replaced_by(&self, other: &MoodleFile) -> bool {
+        match (&self.hash, &other.hash, &self.version, &other.version, self.size, other.size) {
+            (Some(a), Some(b), _, _, _, _) => a != b,
+            (_, _, Some(a), Some(b), _, _) => a != b,
+            (_, _, _, _, Some(a), Some(b)) => a != b,
+            _ => false
+        }
+    }
+}
+
+impl MoodleActivity {
+    pub fn is_available(&self) -> bool {
+        self.visible && self.restriction.is_none()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn section(&self) -> &str {
+        &self.section
+    }
+
+    pub fn kind_name(&self) -> &str {
+        &self.kind_name
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    pub fn restriction(&self) -> Option<&str> {
+        self.restriction.as_deref()
+    }
+
+    pub fn dates(&self) -> &[String] {
+        &self.dates
+    }
+
+    pub fn files(&self) -> Option<&[MoodleFile]> {
+        self.files.as_deref()
+    }
+}
+
+// The names of the change types, as used by notification filters and in the serialised changes
+pub const CHANGE_KINDS: &[&str] = &["uploaded", "available", "text_changed", "folder_changed", "file_updated"];
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MoodleChange {
+    Uploaded{ kind: String, name: String },
+    Available{ kind: String, name: String },
+    TextChanged{ kind: String, name: String, excerpt: String },
+    FolderChanged{ name: String, added: Vec<String>, removed: Vec<String>, replaced: Vec<String> },
+    FileUpdated{ name: String }
+}
+
+impl MoodleChange {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MoodleChange::Uploaded{ .. } => "uploaded",
+            MoodleChange::Available{ .. } => "available",
+            MoodleChange::TextChanged{ .. } => "text_changed",
+            MoodleChange::FolderChanged{ .. } => "folder_changed",
+            MoodleChange::FileUpdated{ .. } => "file_updated"
+        }
+    }
+}
+
+impl fmt::Display for MoodleChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoodleChange::Uploaded{ kind, name } => write!(f, "New \"{}\" uploaded: \"{}\"", kind, name),
+            MoodleChange::Available{ kind, name } => write!(f, "\"{}\" now available: \"{}\"", kind, name),
+            MoodleChange::TextChanged{ kind, name, excerpt } => write!(f, "\"{}\" changed: \"{}\"\n{}", kind, name, excerpt),
+            MoodleChange::FolderChanged{ name, added, removed, replaced } => {
+                write!(f, "Folder \"{}\" changed:", name)?;
+                for file in added {
+                    write!(f, "\nAdded \"{}\"", file)?;
+                }
+                for file in removed {
+                    write!(f, "\nRemoved \"{}\"", file)?;
+                }
+                for file in replaced {
+                    write!(f, "\nReplaced \"{}\"", file)?;
+                }
+                Ok(())
+            },
+            MoodleChange::FileUpdated{ name } => write!(f, "Updated: \"{}\"", name)
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, PartialEq)]
 pub enum MoodleAuthConf {
     ShibbolethUser(String, String)
 }
 
+// Keeps the password out of logs
+impl fmt::Debug for MoodleAuthConf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoodleAuthConf::ShibbolethUser(user, _) => f.debug_tuple("ShibbolethUser").field(user).field(&"<redacted>").finish()
+        }
+    }
+}
+
 #[cfg(test)]
 use std::fs::read_to_string;
 
@@ -248,13 +941,15 @@ fn test_moodle_course_diff() {
         id: 0,
         name: "Test".to_string(),
         url: "https://example.com".to_string(),
-        content: origin
+        content: origin,
+        activities: Vec::new()
     };
     let target = MoodleCourseData {
         id: 0,
         name: "Test".to_string(),
         url: "https://example.com".to_string(),
-        content: target
+        content: target,
+        activities: Vec::new()
     };
 
     let diff = origin.user_diff(&target).expect("Test files are identical");
@@ -262,10 +957,181 @@ fn test_moodle_course_diff() {
     assert_eq!(diff, "New \"Datei\" uploaded: \"NEW CONTENT!\"\nNew \"Textseite\" uploaded: \"MORE CONTENT!\"\n");
 }
 
+#[test]
+fn test_course_from_html() {
+    let activity = r#"<ul><li class="activity resource modtype_resource" id="module-7"><div class="activityinstance"><a class="aalink" href="https://www.moodle.tum.de/mod/resource/view.php?id=7"><span class="instancename">Sheet 3<span class="accesshide "> Datei</span></span></a></div></li></ul>"#;
+
+    let page = MoodleCourseData::from_html("page.html", format!(r#"<html><body><h1>Analysis 1</h1><div id="page-content">{}</div></body></html>"#, activity));
+    assert_eq!(page.name(), "Analysis 1");
+    assert!(page.content().starts_with("<div id=\"page-content\">"));
+    assert_eq!(page.activities()[0].name(), "Sheet 3");
+
+    let fragment = MoodleCourseData::from_html("fragment.html", activity.to_string());
+    assert_eq!(fragment.activities()[0].name(), "Sheet 3");
+}
+
+#[test]
+fn test_moodle_availability_change() {
+    let restricted = r#"<div id="page-content"><ul><li class="activity resource modtype_resource" id="module-7"><div class="activityinstance"><div class="dimmed_text"><span class="instancename">Sheet 3<span class="accesshide "> Datei</span></span></div></div><div class="availabilityinfo isrestricted">Not available unless: It is on or after 1 May</div></li></ul></div>"#;
+    let available = r#"<div id="page-content"><ul><li class="activity resource modtype_resource" id="module-7"><div class="activityinstance"><a class="aalink" href="https://www.moodle.tum.de/mod/resource/view.php?id=7"><span class="instancename">Sheet 3<span class="accesshide "> Datei</span></span></a></div></li></ul></div>"#;
+
+    let origin = MoodleCourseData::from_html("https://example.com", restricted.to_string());
+    let target = MoodleCourseData::from_html("https://example.com", available.to_string());
+
+    assert_eq!(origin.activities[0].name, "Sheet 3");
+    assert!(!origin.activities[0].is_available());
+    assert!(target.activities[0].is_available());
+    assert_eq!(origin.changes(&target), vec![MoodleChange::Available{ kind: "Datei".to_string(), name: "Sheet 3".to_string() }]);
+    assert!(target.changes(&origin).is_empty());
+}
+
+#[test]
+fn test_moodle_upload_same_name() {
+    let slides = |id: u32| format!(r#"<li class="activity resource modtype_resource" id="module-{0}"><div class="activityinstance"><a class="aalink" href="https://www.moodle.tum.de/mod/resource/view.php?id={0}"><span class="instancename">Slides<span class="accesshide "> Datei</span></span></a></div></li>"#, id);
+    let origin = MoodleCourseData::from_html("https://example.com", format!(r#"<div id="page-content"><ul>{}</ul></div>"#, slides(11)));
+    let target = MoodleCourseData::from_html("https://example.com", format!(r#"<div id="page-content"><ul>{}{}</ul></div>"#, slides(11), slides(12)));
+
+    assert_eq!(origin.changes(&target), vec![MoodleChange::Uploaded{ kind: "Datei".to_string(), name: "Slides".to_string() }]);
+    assert!(target.changes(&target).is_empty());
+}
+
+#[test]
+fn test_moodle_label_change() {
+    let label = |text: &str| format!(r#"<div id="page-content"><ul><li class="activity label modtype_label" id="module-9"><div class="contentwithoutlink"><p>Exam information</p><p>{}</p></div></li></ul></div>"#, text);
+    let origin = MoodleCourseData::from_html("https://example.com", label("Room: MW 0001"));
+    let target = MoodleCourseData::from_html("https://example.com", label("Room: MW 2001"));
+
+    assert_eq!(origin.activities[0].text.as_deref(), Some("Exam information\nRoom: MW 0001"));
+    assert_eq!(origin.changes(&target).iter().filter_map(|c| match c {
+        MoodleChange::TextChanged{ excerpt, .. } => Some(excerpt.as_str()),
+        _ => None
+    }).collect::<Vec<_>>(), vec!["> Room: MW 2001"]);
+}
+
+#[test]
+fn test_moodle_folder_change() {
+    let listing = |rev: u32| format!(r#"<div role="main"><div class="filemanager"><ul>
+        <li><span class="fp-filename-icon"><a href="https://www.moodle.tum.de/pluginfile.php/5/mod_folder/content/{0}/Sheet%201.pdf?forcedownload=1"><span class="fp-filename">Sheet 1.pdf</span></a></span></li>
+        <li><span class="fp-filename-icon"><a href="https://www.moodle.tum.de/pluginfile.php/5/mod_folder/content/{0}/Sheet%202.pdf?forcedownload=1"><span class="fp-filename">Sheet 2.pdf</span></a></span></li>
+        </ul></div></div>"#, rev);
+
+    let mut origin_files = parse_folder_files(&listing(1));
+    let mut target_files = parse_folder_files(&listing(2));
+    assert_eq!(origin_files.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), vec!["Sheet 1.pdf", "Sheet 2.pdf"]);
+
+    origin_files[0].version = Some("\"aaa\"".to_string());
+    origin_files[1].version = Some("\"bbb\"".to_string());
+    target_files[0].version = Some("\"aaa\"".to_string());
+    target_files[1].version = Some("\"ccc\"".to_string());
+    target_files.push(MoodleFile { name: "Sheet 3.pdf".to_string(), url: "https://example.com/3".to_string(), version: None, size: None, hash: None });
+
+    let course = |files: Vec<MoodleFile>| MoodleCourseData::with_activities(vec![MoodleActivity {
+        id: 3,
+        name: "Exercise sheets".to_string(),
+        section: "Exercises".to_string(),
+        kind: "folder".to_string(),
+        kind_name: "Verzeichnis".to_string(),
+        url: None,
+        text: None,
+        files: Some(files),
+        visible: true,
+        restriction: None,
+        dates: Vec::new()
+    }]);
+
+    assert_eq!(course(origin_files).user_diff(&course(target_files)).unwrap(), "Folder \"Exercise sheets\" changed:\nAdded \"Sheet 3.pdf\"\nReplaced \"Sheet 2.pdf\"\n");
+}
+
+#[test]
+fn test_moodle_file_replaced() {
+    let file = |version: Option<&str>, hash: Option<&str>| MoodleFile {
+        name: file_name("https://www.moodle.tum.de/pluginfile.php/5/mod_resource/content/2/Sheet%203.pdf?forcedownload=1"),
+        url: String::new(),
+        version: version.map(|v| v.to_string()),
+        size: Some(100),
+        hash: hash.map(|h| h.to_string())
+    };
+
+    assert_eq!(file(None, None).name, "Sheet 3.pdf");
+    assert!(file(Some("a"), None).replaced_by(&file(Some("b"), None)));
+    assert!(!file(Some("a"), Some("x")).replaced_by(&file(Some("b"), Some("x"))));
+    assert!(!file(None, None).replaced_by(&file(None, None)));
+}
+
+#[test]
+fn test_session_expired() {
+    let course = reqwest::Url::parse("https://www.moodle.tum.de/course/view.php?id=1").unwrap();
+    let login = reqwest::Url::parse("https://www.moodle.tum.de/login/index.php").unwrap();
+
+    let logged_in = r#"<html><body class="course-1"><div class="usermenu"><span class="userbutton">Max</span></div></body></html>"#;
+    let logged_out = r#"<html><body class="notloggedin"><div class="usermenu"><span class="login">Log in</span></div></body></html>"#;
+    let guest = r#"<html><body class="course-1"><div class="logininfo">You are currently using guest access (<a href="/login/index.php">Log in</a>)</div></body></html>"#;
+
+    assert!(!session_expired(&course, logged_in));
+    assert!(session_expired(&login, logged_in));
+    assert!(session_expired(&course, logged_out));
+    assert!(session_expired(&course, guest));
+    assert!(session_expired(&course, "<html><body></body></html>"));
+}
+
+#[test]
+fn test_auth_conf_redacted() {
+    let auth = MoodleAuthConf::ShibbolethUser("ab12cde".to_string(), "hunter2".to_string());
+    assert_eq!(format!("{:?}", auth), "ShibbolethUser(\"ab12cde\", \"<redacted>\")");
+}
+
 #[derive(Debug)]
 pub enum MoodleErr {
-    Network,
-    Login,
-    CourseNotFound,
-    Auth
+    Network{ url: String, source: reqwest::Error },
+    Status{ url: String, status: reqwest::StatusCode },
+    Login{ attempts: u32, last: Box<MoodleErr> },
+    CourseNotFound{ id: u32 },
+    Auth{ step: LoginStep },
+    SessionExpired{ url: String }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoginStep {
+    IdpRedirect,
+    LoginForm,
+    Credentials
+}
+
+impl MoodleErr {
+    fn network(url: &str, source: reqwest::Error) -> Self {
+        MoodleErr::Network{ url: url.to_string(), source }
+    }
+}
+
+impl fmt::Display for MoodleErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoodleErr::Network{ url, source } => write!(f, "request to {} failed: {}", url, source),
+            MoodleErr::Status{ url, status } => write!(f, "request to {} returned {}", url, status),
+            MoodleErr::Login{ attempts, last } => write!(f, "login failed after {} attempts: {}", attempts, last),
+            MoodleErr::CourseNotFound{ id } => write!(f, "course {} not found", id),
+            MoodleErr::Auth{ step } => write!(f, "authentication failed at {}", step),
+            MoodleErr::SessionExpired{ url } => write!(f, "session still expired after logging in again while fetching {}", url)
+        }
+    }
+}
+
+impl Error for MoodleErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MoodleErr::Network{ source, .. } => Some(source),
+            MoodleErr::Login{ last, .. } => Some(last.as_ref()),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for LoginStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoginStep::IdpRedirect => write!(f, "the identity provider redirect (no login form found)"),
+            LoginStep::LoginForm => write!(f, "the login form (no CSRF token found)"),
+            LoginStep::Credentials => write!(f, "the credential check (no SAML response, wrong user or password?)")
+        }
+    }
 }
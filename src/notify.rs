@@ -0,0 +1,222 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use serenity::http::Http;
+use serenity::model::id::ChannelId;
+
+use rand::{thread_rng, Rng};
+
+use tokio::sync::Mutex;
+
+use tracing::error;
+
+use crate::conf::SharedConf;
+use crate::feed::History;
+use crate::metrics::Metrics;
+use crate::moodle::*;
+
+pub type NotifyErr = Box<dyn Error + Send + Sync>;
+
+// One of the configured flavour texts, or nothing if there are none
+pub fn random_resp(responses: &[String]) -> &str {
+    if responses.is_empty() {
+        return "";
+    }
+    &responses[thread_rng().gen_range(0..responses.len())]
+}
+
+// Everything a sink needs to know about one poll of one course that found changes
+#[derive(Clone, Debug)]
+pub struct ChangeEvent {
+    // All channels watching the course
+    pub channels: Vec<ChannelId>,
+    pub course_id: u32,
+    pub course_name: String,
+    pub course_url: String,
+    pub time: DateTime<Utc>,
+    pub changes: Vec<MoodleChange>
+}
+
+impl ChangeEvent {
+    pub fn new(channels: Vec<ChannelId>, course: &MoodleCourseData, changes: Vec<MoodleChange>) -> Self {
+        Self {
+            channels,
+            course_id: course.id(),
+            course_name: course.name().to_string(),
+            course_url: course.url().to_string(),
+            time: Utc::now(),
+            changes
+        }
+    }
+
+    #[cfg(test)]
+    pub fn example() -> Self {
+        Self {
+            channels: vec![ChannelId(1)],
+            course_id: 42,
+            course_name: "Test".to_string(),
+            course_url: "https://moodle.example/course/view.php?id=42".to_string(),
+            time: Utc::now(),
+            changes: vec![MoodleChange::FileUpdated { name: "Sheet 1".to_string() }]
+        }
+    }
+
+    pub fn title(&self) -> String {
+        format!("Update in course {}", self.course_name)
+    }
+
+    pub fn text(&self) -> String {
+        self.changes.iter().map(|c| format!("{}\n", c)).collect()
+    }
+}
+
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyErr>;
+}
+
+// Hands every event to all sinks, a failing sink doesn't keep the others from being notified
+#[derive(Clone, Default)]
+pub struct Notifiers {
+    sinks: Vec<Arc<dyn Notifier>>
+}
+
+impl Notifiers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add<N: Notifier + 'static>(&mut self, sink: N) {
+        self.sinks.push(Arc::new(sink));
+    }
+
+    pub async fn notify(&self, event: &ChangeEvent) -> usize {
+        let mut failed = 0;
+        for sink in &self.sinks {
+            if let Err(e) = sink.notify(event).await {
+                error!(course = event.course_id, "Failed to notify {}: {}", sink.name(), e);
+                failed += 1;
+            }
+        }
+        failed
+    }
+}
+
+// Posts an embed into every channel that is subscribed to the course
+pub struct DiscordNotifier {
+    http: Arc<Http>,
+    conf: Arc<SharedConf>,
+    metrics: Metrics
+}
+
+impl DiscordNotifier {
+    pub fn new(http: Arc<Http>, conf: Arc<SharedConf>, metrics: Metrics) -> Self {
+        Self {
+            http,
+            conf,
+            metrics
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for DiscordNotifier {
+    fn name(&self) -> &str {
+        "discord"
+    }
+
+    // Posts only the kinds of changes each channel asked for, with the channel's own responses, and reports the last failure
+    async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyErr> {
+        let conf = self.conf.get();
+        let mut res = Ok(());
+
+        for &channel in &event.channels {
+            let mut event = event.clone();
+            if let Some(channel) = conf.channel(channel) {
+                event.changes.retain(|change| channel.wants(change));
+            }
+            if event.changes.is_empty() {
+                continue;
+            }
+            let resp = random_resp(conf.responses(channel)).to_string();
+
+            if let Err(e) = channel.send_message(&self.http, |m| {
+                m.embed(|e| {
+                    e.title(event.title());
+                    e.url(&event.course_url);
+                    e.description(format!("{}\n{}", event.text(), resp));
+                    e
+                });
+                m
+            }).await {
+                self.metrics.discord_send_error();
+                res = Err(e.into());
+            }
+        }
+
+        res
+    }
+}
+
+// Keeps the change history served as Atom feeds
+pub struct FeedNotifier {
+    history: Arc<Mutex<History>>
+}
+
+impl FeedNotifier {
+    pub fn new(history: Arc<Mutex<History>>) -> Self {
+        Self {
+            history
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for FeedNotifier {
+    fn name(&self) -> &str {
+        "feed"
+    }
+
+    async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyErr> {
+        self.history.lock().await.record(event);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct RecordingNotifier {
+    events: Arc<Mutex<Vec<u32>>>,
+    fail: bool
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Notifier for RecordingNotifier {
+    fn name(&self) -> &str {
+        "recording"
+    }
+
+    async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyErr> {
+        if self.fail {
+            return Err("sink unavailable".into());
+        }
+        self.events.lock().await.push(event.course_id);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_notifiers() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut notifiers = Notifiers::new();
+    notifiers.add(RecordingNotifier { events: events.clone(), fail: true });
+    notifiers.add(RecordingNotifier { events: events.clone(), fail: false });
+
+    assert_eq!(notifiers.notify(&ChangeEvent::example()).await, 1);
+    assert_eq!(*events.lock().await, vec![42]);
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use serenity::model::id::*;
+
+#[derive(Clone, Debug, Default)]
+pub struct Rule {
+    pub users: Vec<UserId>,
+    pub roles: Vec<RoleId>
+}
+
+impl Rule {
+    fn matches(&self, user: UserId, roles: &[RoleId]) -> bool {
+        self.users.contains(&user) || roles.iter().any(|r| self.roles.contains(r))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Permissions {
+    // Allowed to use every command
+    pub all: Rule,
+    // Only the users and roles of its rule (and those of all) may use a command with a rule
+    pub commands: HashMap<String, Rule>
+}
+
+impl Permissions {
+    // Commands without a rule stay open to everyone, except send and switching the account, which then are limited to the
+    // users and roles of all, as either reaches beyond the channel
+    pub fn allows(&self, command: &str, user: UserId, roles: &[RoleId]) -> bool {
+        if self.all.matches(user, roles) {
+            return true;
+        }
+
+        match self.commands.get(command) {
+            Some(rule) => rule.matches(user, roles),
+            None => !matches!(command, "send" | "account")
+        }
+    }
+}
+
+#[test]
+fn test_permissions() {
+    let mut permissions = Permissions {
+        all: Rule {
+            users: vec![UserId(1)],
+            roles: vec![RoleId(10)]
+        },
+        commands: HashMap::new()
+    };
+    permissions.commands.insert("watch".to_string(), Rule {
+        users: vec![UserId(2)],
+        roles: vec![RoleId(20)]
+    });
+
+    assert!(permissions.allows("send", UserId(1), &[]));
+    assert!(permissions.allows("send", UserId(3), &[RoleId(10)]));
+    assert!(!permissions.allows("send", UserId(2), &[RoleId(20)]));
+    assert!(permissions.allows("account", UserId(1), &[]));
+    assert!(!permissions.allows("account", UserId(3), &[]));
+
+    assert!(permissions.allows("watch", UserId(2), &[]));
+    assert!(permissions.allows("watch", UserId(3), &[RoleId(30), RoleId(20)]));
+    assert!(!permissions.allows("watch", UserId(3), &[RoleId(30)]));
+
+    assert!(permissions.allows("unwatch", UserId(3), &[]));
+    assert!(permissions.allows("status", UserId(3), &[]));
+
+    permissions.commands.insert("export".to_string(), Rule {
+        users: vec![UserId(2)],
+        roles: Vec::new()
+    });
+    assert!(permissions.allows("export", UserId(2), &[]));
+    assert!(!permissions.allows("export", UserId(3), &[]));
+}
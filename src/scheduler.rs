@@ -0,0 +1,153 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration, Instant};
+
+use tracing::error;
+
+// Runs the poll loop at most once per process, restarting it if a poll panics
+pub struct Scheduler {
+    state: Arc<Mutex<SchedulerState>>,
+    cancel: Arc<Notify>
+}
+
+struct SchedulerState {
+    status: SchedulerStatus,
+    polls: u64,
+    restarts: u64,
+    last_poll: Option<Instant>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SchedulerStatus {
+    Idle,
+    Running,
+    Cancelled
+}
+
+#[derive(Clone, Debug)]
+pub struct SchedulerReport {
+    pub status: SchedulerStatus,
+    pub polls: u64,
+    pub restarts: u64,
+    pub last_poll: Option<Instant>
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SchedulerState {
+                status: SchedulerStatus::Idle,
+                polls: 0,
+                restarts: 0,
+                last_poll: None
+            })),
+            cancel: Arc::new(Notify::new())
+        }
+    }
+
+    // Returns false without doing anything if the scheduler was already started
+    pub fn start<F, Fut>(&self, interval: Duration, poll: F) -> bool
+        where F: Fn() -> Fut + Send + Sync + 'static, Fut: Future<Output = ()> + Send + 'static {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.status != SchedulerStatus::Idle {
+                return false;
+            }
+            state.status = SchedulerStatus::Running;
+        }
+
+        let state = self.state.clone();
+        let cancel = self.cancel.clone();
+        let poll = Arc::new(poll);
+
+        tokio::spawn(async move { loop {
+            let task_state = state.clone();
+            let task_poll = poll.clone();
+            let mut task = tokio::spawn(async move { loop {
+                sleep(interval).await;
+                task_poll().await;
+
+                let mut state = task_state.lock().unwrap();
+                state.polls += 1;
+                state.last_poll = Some(Instant::now());
+            }});
+
+            tokio::select! {
+                res = &mut task => {
+                    if let Err(e) = res {
+                        error!("Poll loop stopped unexpectedly, restarting: {}", e);
+                        state.lock().unwrap().restarts += 1;
+                    }
+                },
+                _ = cancel.notified() => {
+                    task.abort();
+                    state.lock().unwrap().status = SchedulerStatus::Cancelled;
+                    break;
+                }
+            }
+        }});
+
+        true
+    }
+
+    pub fn cancel(&self) {
+        if self.state.lock().unwrap().status == SchedulerStatus::Running {
+            self.cancel.notify_one();
+        }
+    }
+
+    pub fn report(&self) -> SchedulerReport {
+        let state = self.state.lock().unwrap();
+        SchedulerReport {
+            status: state.status,
+            polls: state.polls,
+            restarts: state.restarts,
+            last_poll: state.last_poll
+        }
+    }
+}
+
+impl fmt::Display for SchedulerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchedulerStatus::Idle => write!(f, "idle"),
+            SchedulerStatus::Running => write!(f, "running"),
+            SchedulerStatus::Cancelled => write!(f, "cancelled")
+        }
+    }
+}
+
+impl fmt::Display for SchedulerReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "scheduler {}, {} polls, {} restarts", self.status, self.polls, self.restarts)?;
+        match self.last_poll {
+            Some(t) => write!(f, ", last poll {} seconds ago", t.elapsed().as_secs()),
+            None => write!(f, ", no polls yet")
+        }
+    }
+}
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[tokio::test]
+async fn test_scheduler_starts_once() {
+    let count = Arc::new(AtomicU64::new(0));
+    let scheduler = Scheduler::new();
+
+    let c = count.clone();
+    assert!(scheduler.start(Duration::from_millis(10), move || { let c = c.clone(); async move { c.fetch_add(1, Ordering::SeqCst); } }));
+    let c = count.clone();
+    assert!(!scheduler.start(Duration::from_millis(10), move || { let c = c.clone(); async move { c.fetch_add(100, Ordering::SeqCst); } }));
+
+    sleep(Duration::from_millis(55)).await;
+    scheduler.cancel();
+    sleep(Duration::from_millis(10)).await;
+
+    let report = scheduler.report();
+    assert_eq!(report.status, SchedulerStatus::Cancelled);
+    assert!(report.polls >= 1 && count.load(Ordering::SeqCst) < 100);
+}
@@ -0,0 +1,143 @@
+use std::convert::TryFrom;
+
+use serde_json::{json, Value};
+
+use serenity::http::Http;
+use serenity::model::interactions::*;
+
+use tracing::{error, info};
+
+// Discord's application command option types
+const OPTION_STRING: u8 = 3;
+const OPTION_INTEGER: u8 = 4;
+
+// Interaction response types
+const DEFERRED_CHANNEL_MESSAGE: u8 = 5;
+
+// A week
+const MAX_TIMER: u64 = 7 * 24 * 3600;
+
+#[derive(Debug, PartialEq)]
+pub enum SlashCommand {
+    Watch(u32),
+    Unwatch(u32),
+    Timer(u64),
+    // Sets the members to make groups of, makes the groups if None
+    MakeGroups(Option<Vec<String>>),
+    Send(String)
+}
+
+impl SlashCommand {
+    // None for unknown commands, missing options and values out of the range given in the definitions
+    pub fn parse(data: &ApplicationCommandInteractionData) -> Option<Self> {
+        let option = |name: &str| data.options.iter().find(|o| o.name == name).and_then(|o| o.value.as_ref());
+        let int = |name: &str| option(name).and_then(|v| v.as_u64());
+        let course = || int("course").filter(|id| *id >= 1).and_then(|id| u32::try_from(id).ok());
+        let str = |name: &str| option(name).and_then(|v| v.as_str()).map(|s| s.to_string());
+
+        match data.name.as_str() {
+            "watch" => course().map(SlashCommand::Watch),
+            "unwatch" => course().map(SlashCommand::Unwatch),
+            "timer" => int("seconds").filter(|s| (1..=MAX_TIMER).contains(s)).map(SlashCommand::Timer),
+            "makegroups" => Some(SlashCommand::MakeGroups(str("members").map(|s| s.split_whitespace().map(|m| m.to_string()).collect()))),
+            "send" => str("text").map(SlashCommand::Send),
+            _ => None
+        }
+    }
+}
+
+// Course ids are plain integer options without autocompletion: serenity 0.10 fails to parse autocomplete interactions, so there
+// would be nothing to answer them with until the bot moves to a newer serenity. Choices would be shared by all guilds, are
+// limited to 25 and take up to an hour to update, so /unwatch takes any id as well.
+fn definitions() -> Vec<Value> {
+    let course = |description: &str| json!({
+        "type": OPTION_INTEGER,
+        "name": "course",
+        "description": description,
+        "required": true,
+        "min_value": 1,
+        "max_value": u32::MAX
+    });
+
+    let commands = vec![
+        json!({
+            "name": "watch",
+            "description": "Post changes of a Moodle course in this channel",
+            "options": [course("Moodle course id, as in course/view.php?id=<id>")]
+        }),
+        json!({
+            "name": "unwatch",
+            "description": "Stop posting changes of a Moodle course in this channel",
+            "options": [course("Moodle course id of a watched course")]
+        }),
+        json!({
+            "name": "timer",
+            "description": "Bark after some time",
+            "options": [{ "type": OPTION_INTEGER, "name": "seconds", "description": "Seconds to wait", "required": true, "min_value": 1, "max_value": MAX_TIMER }]
+        }),
+        json!({
+            "name": "makegroups",
+            "description": "Set the members to split into groups, or make random groups of the members set before",
+            "options": [{ "type": OPTION_STRING, "name": "members", "description": "Members separated by spaces", "required": false }]
+        }),
+        json!({
+            "name": "send",
+            "description": "Post an announcement in every channel that watches courses",
+            "options": [{ "type": OPTION_STRING, "name": "text", "description": "Text of the announcement", "required": true }]
+        })
+    ];
+
+    // Interactions from DMs come without the member and guild serenity 0.10 requires, so the commands are left out of DMs
+    commands.into_iter().map(|mut command| {
+        command["dm_permission"] = json!(false);
+        command
+    }).collect()
+}
+
+// Global commands take up to an hour to show up in all guilds, registering an existing command again replaces it
+pub async fn register(http: &Http, application_id: u64) {
+    for command in definitions() {
+        match http.create_global_application_command(application_id, &command).await {
+            Ok(command) => info!("Registered slash command /{}", command.name),
+            Err(e) => error!("Failed to register slash command /{}: {}", command["name"], e)
+        }
+    }
+}
+
+// The real reply follows with reply(), as fetching a course can take longer than the three seconds Discord waits for a response
+pub async fn defer(http: &Http, interaction: &Interaction) -> serenity::Result<()> {
+    http.create_interaction_response(interaction.id.0, &interaction.token, &json!({ "type": DEFERRED_CHANNEL_MESSAGE })).await
+}
+
+pub async fn reply(http: &Http, application_id: u64, interaction: &Interaction, text: &str) -> serenity::Result<()> {
+    http.edit_original_interaction_response(application_id, &interaction.token, &json!({ "content": text })).await?;
+    Ok(())
+}
+
+#[test]
+fn test_slash_parse() {
+    let data = |name: &str, options: Value| serde_json::from_value::<ApplicationCommandInteractionData>(json!({
+        "id": "1",
+        "name": name,
+        "options": options
+    })).unwrap();
+
+    assert_eq!(SlashCommand::parse(&data("watch", json!([{ "name": "course", "value": 12345 }]))), Some(SlashCommand::Watch(12345)));
+    assert_eq!(SlashCommand::parse(&data("timer", json!([{ "name": "seconds", "value": 60 }]))), Some(SlashCommand::Timer(60)));
+    assert_eq!(SlashCommand::parse(&data("makegroups", json!([]))), Some(SlashCommand::MakeGroups(None)));
+    assert_eq!(SlashCommand::parse(&data("makegroups", json!([{ "name": "members", "value": "Ann  Bo Cy" }]))),
+        Some(SlashCommand::MakeGroups(Some(vec!["Ann".to_string(), "Bo".to_string(), "Cy".to_string()]))));
+    assert_eq!(SlashCommand::parse(&data("send", json!([{ "name": "text", "value": "No lecture today" }]))), Some(SlashCommand::Send("No lecture today".to_string())));
+    assert_eq!(SlashCommand::parse(&data("unwatch", json!([]))), None);
+    assert_eq!(SlashCommand::parse(&data("watch", json!([{ "name": "course", "value": 4294967297u64 }]))), None);
+    assert_eq!(SlashCommand::parse(&data("timer", json!([{ "name": "seconds", "value": 0 }]))), None);
+    assert_eq!(SlashCommand::parse(&data("status", json!([]))), None);
+}
+
+#[test]
+fn test_slash_definitions() {
+    let commands = definitions();
+    let names = commands.iter().map(|c| c["name"].as_str().unwrap()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["watch", "unwatch", "timer", "makegroups", "send"]);
+    assert!(commands.iter().all(|c| c["dm_permission"] == json!(false)));
+}
@@ -0,0 +1,109 @@
+use std::fmt;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::moodle::*;
+use crate::notify::*;
+
+pub const SIGNATURE_HEADER: &str = "X-Poodle-Signature";
+
+// Webhooks are called from the poll loop, so a hanging endpoint must not hold it up for long
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, PartialEq)]
+pub struct WebhookConf {
+    pub name: String,
+    pub url: String,
+    pub secret: Option<String>,
+    pub courses: Vec<u32>
+}
+
+impl fmt::Debug for WebhookConf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebhookConf")
+            .field("name", &self.name)
+            .field("url", &self.url)
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .field("courses", &self.courses)
+            .finish()
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    course: WebhookCourse<'a>,
+    time: DateTime<Utc>,
+    text: String,
+    changes: &'a [MoodleChange]
+}
+
+#[derive(Serialize)]
+struct WebhookCourse<'a> {
+    id: u32,
+    name: &'a str,
+    url: &'a str
+}
+
+// POSTs every change as JSON, signed with HMAC-SHA256 of the body if a secret is configured
+pub struct WebhookNotifier {
+    conf: WebhookConf,
+    client: reqwest::Client
+}
+
+impl WebhookNotifier {
+    pub fn new(conf: WebhookConf) -> Self {
+        Self {
+            conf,
+            client: reqwest::Client::builder().timeout(TIMEOUT).build().expect("Failed to construct HTTP client")
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        &self.conf.name
+    }
+
+    async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyErr> {
+        if !self.conf.courses.is_empty() && !self.conf.courses.contains(&event.course_id) {
+            return Ok(());
+        }
+
+        let body = serde_json::to_string(&WebhookPayload {
+            course: WebhookCourse {
+                id: event.course_id,
+                name: &event.course_name,
+                url: &event.course_url
+            },
+            time: event.time,
+            text: event.text(),
+            changes: &event.changes
+        })?;
+
+        let mut req = self.client.post(&self.conf.url).header("Content-Type", "application/json");
+        if let Some(secret) = &self.conf.secret {
+            req = req.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &body)));
+        }
+
+        req.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_webhook_signature() {
+    // RFC 4231, test case 2
+    assert_eq!(sign("Jefe", "what do ya want for nothing?"), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+}